@@ -97,6 +97,9 @@ pub fn create_window_builder(
         WindowMode::Maximized => {
             window_builder = window_builder.with_maximized(true);
         }
+        WindowMode::BorderlessFullscreen => {
+            window_builder = window_builder.with_maximized(true).with_decorations(false);
+        }
         _ => {
             window_builder = window_builder.with_inner_size(winit::dpi::LogicalSize {
                 width: config.window.width,
@@ -150,5 +153,23 @@ pub fn configure_window(winit_window: Window, config: &Config) -> Window {
 
     winit_window.set_blur(config.window.blur);
 
+    if config.window.mode == WindowMode::Windowed {
+        if let Some(monitor) = winit_window.current_monitor() {
+            let monitor_size: winit::dpi::LogicalSize<i32> =
+                monitor.size().to_logical(monitor.scale_factor());
+            if let Some((x, y)) = config
+                .window
+                .resolved_position((monitor_size.width, monitor_size.height))
+            {
+                let origin: winit::dpi::LogicalPosition<i32> =
+                    monitor.position().to_logical(monitor.scale_factor());
+                winit_window.set_outer_position(winit::dpi::LogicalPosition {
+                    x: origin.x + x,
+                    y: origin.y + y,
+                });
+            }
+        }
+    }
+
     winit_window
 }
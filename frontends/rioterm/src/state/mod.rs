@@ -13,7 +13,9 @@ use rio_backend::config::colors::{
     AnsiColor, ColorArray, Colors, NamedColor,
 };
 use rio_backend::config::Config;
-use rio_backend::sugarloaf::{Sugar, SugarCursor, SugarDecoration, SugarStyle};
+use rio_backend::sugarloaf::{
+    StrikethroughInfo, Sugar, SugarCursor, SugarDecoration, SugarStyle,
+};
 use rio_backend::sugarloaf::{SugarGraphic, Sugarloaf};
 use std::collections::HashMap;
 use std::time::{Duration, Instant};
@@ -198,7 +200,7 @@ impl State {
         if flags.contains(Flags::UNDERLINE) {
             decoration = SugarDecoration::Underline;
         } else if flags.contains(Flags::STRIKEOUT) {
-            decoration = SugarDecoration::Strikethrough;
+            decoration = SugarDecoration::Strikethrough(StrikethroughInfo::default());
         }
 
         let background_color = if self.dynamic_background.2
@@ -446,6 +448,8 @@ impl State {
                 id: media.id,
                 width: media.width,
                 height: media.height,
+                offset: (0., 0.),
+                crop: None,
             }),
             ..Sugar::default()
         }
@@ -169,6 +169,7 @@ impl Config {
             let content = std::fs::read_to_string(path).unwrap();
             match toml::from_str::<Config>(&content) {
                 Ok(mut decoded) => {
+                    decoded.window = decoded.window.normalized();
                     let theme = &decoded.theme;
                     if theme.is_empty() {
                         return Ok(decoded);
@@ -245,6 +246,7 @@ impl Config {
             let content = std::fs::read_to_string(path).unwrap();
             match toml::from_str::<Config>(&content) {
                 Ok(mut decoded) => {
+                    decoded.window = decoded.window.normalized();
                     let theme = &decoded.theme;
                     if theme.is_empty() {
                         return decoded;
@@ -278,6 +280,7 @@ impl Config {
             let content = std::fs::read_to_string(path).unwrap();
             match toml::from_str::<Config>(&content) {
                 Ok(mut decoded) => {
+                    decoded.window = decoded.window.normalized();
                     let theme = &decoded.theme;
                     let theme_path = config_dir_path().join("themes");
                     if !theme.is_empty() {
@@ -677,6 +680,128 @@ mod tests {
         assert_eq!(result.colors.cursor, colors::defaults::cursor());
     }
 
+    #[test]
+    fn test_background_image_fit_and_position() {
+        let result = create_temporary_config(
+            "background-fit",
+            r#"
+            [window]
+            background-fit = "tile"
+            background-position = "BottomRight"
+            [window.background-image]
+            path = "my-image-path.png"
+        "#,
+        );
+
+        assert_eq!(result.window.background_fit, window::BackgroundFit::Tile);
+        assert_eq!(
+            result.window.background_position,
+            window::BackgroundPosition::BottomRight
+        );
+
+        // Defaults keep the image bounds as configured.
+        let defaults = Window::default();
+        assert_eq!(defaults.background_fit, window::BackgroundFit::Stretch);
+        assert_eq!(
+            defaults.background_position,
+            window::BackgroundPosition::TopLeft
+        );
+    }
+
+    #[test]
+    fn test_window_opacity_is_clamped() {
+        let result = create_temporary_config(
+            "opacity-above-range",
+            r#"
+            [window]
+            opacity = 1.5
+            blur = true
+        "#,
+        );
+        assert_eq!(result.window.opacity, 1.0);
+        // Blur has no effect on an opaque window.
+        assert!(!result.window.blur);
+
+        let result = create_temporary_config(
+            "opacity-below-range",
+            r#"
+            [window]
+            opacity = -0.5
+            blur = true
+        "#,
+        );
+        assert_eq!(result.window.opacity, 0.0);
+        assert!(result.window.blur);
+
+        let window = Window {
+            opacity: f32::NAN,
+            ..Window::default()
+        }
+        .normalized();
+        assert_eq!(window.opacity, default_opacity());
+    }
+
+    #[test]
+    fn test_window_borderless_fullscreen_mode() {
+        let result = create_temporary_config(
+            "borderless-fullscreen-mode",
+            r#"
+            [window]
+            mode = "borderless-fullscreen"
+        "#,
+        );
+        assert_eq!(
+            result.window.mode,
+            crate::config::window::WindowMode::BorderlessFullscreen
+        );
+        assert!(result.window.is_borderless_fullscreen());
+        assert!(!result.window.is_fullscreen());
+    }
+
+    #[test]
+    fn test_window_position() {
+        use crate::config::window::WindowPosition;
+
+        let result = create_temporary_config(
+            "window-position-centered",
+            r#"
+            [window]
+            width = 600
+            height = 400
+            position = "centered"
+        "#,
+        );
+        assert_eq!(result.window.position, Some(WindowPosition::Centered));
+        assert_eq!(
+            result.window.resolved_position((1920, 1080)),
+            Some((660, 340))
+        );
+        // A window bigger than the monitor is pinned to the origin
+        assert_eq!(result.window.resolved_position((500, 300)), Some((0, 0)));
+
+        let result = create_temporary_config(
+            "window-position-coordinates",
+            r#"
+            [window]
+            position = { coordinates = { x = 20, y = -10 } }
+        "#,
+        );
+        assert_eq!(
+            result.window.position,
+            Some(WindowPosition::Coordinates { x: 20, y: -10 })
+        );
+        assert_eq!(
+            result.window.resolved_position((1920, 1080)),
+            Some((20, -10))
+        );
+
+        let serialized = toml::to_string(&result.window).unwrap();
+        let window: Window = toml::from_str(&serialized).unwrap();
+        assert_eq!(window.position, result.window.position);
+
+        assert_eq!(Window::default().resolved_position((1920, 1080)), None);
+    }
+
     #[test]
     fn test_change_theme() {
         let result = create_temporary_config(
@@ -1,4 +1,5 @@
 use crate::config::defaults::*;
+use log::warn;
 use serde::{Deserialize, Serialize};
 use sugarloaf::ImageProperties;
 
@@ -6,6 +7,10 @@ use sugarloaf::ImageProperties;
 pub enum WindowMode {
     Maximized,
     Fullscreen,
+    // Maximized without decorations, it covers the work area but unlike
+    // Fullscreen the window doesn't take over the display
+    #[serde(alias = "borderless-fullscreen")]
+    BorderlessFullscreen,
     // Windowed will use width and height definition
     #[default]
     Windowed,
@@ -20,6 +25,62 @@ pub enum Decorations {
     Buttonless,
 }
 
+// How the background image is mapped to the window
+#[derive(Default, Clone, Serialize, Deserialize, Copy, Debug, PartialEq)]
+pub enum BackgroundFit {
+    // Cover the window keeping the aspect ratio, cropping the overflow
+    #[serde(alias = "fill")]
+    Fill,
+    // Fit inside the window keeping the aspect ratio
+    #[serde(alias = "fit")]
+    Fit,
+    // Use the image width and height as they are configured
+    #[default]
+    #[serde(alias = "stretch")]
+    Stretch,
+    // Repeat the image across the window
+    #[serde(alias = "tile")]
+    Tile,
+    // Keep the image size and center it on the window
+    #[serde(alias = "center")]
+    Center,
+}
+
+// Anchor of the background image when it doesn't cover the window
+#[derive(Default, Clone, Serialize, Deserialize, Copy, Debug, PartialEq)]
+pub enum BackgroundPosition {
+    #[default]
+    #[serde(alias = "top-left")]
+    TopLeft,
+    #[serde(alias = "top")]
+    Top,
+    #[serde(alias = "top-right")]
+    TopRight,
+    #[serde(alias = "left")]
+    Left,
+    #[serde(alias = "center")]
+    Center,
+    #[serde(alias = "right")]
+    Right,
+    #[serde(alias = "bottom-left")]
+    BottomLeft,
+    #[serde(alias = "bottom")]
+    Bottom,
+    #[serde(alias = "bottom-right")]
+    BottomRight,
+}
+
+// Initial position of the window, only used by Windowed mode
+#[derive(Clone, Serialize, Deserialize, Copy, Debug, PartialEq)]
+pub enum WindowPosition {
+    #[serde(alias = "centered")]
+    Centered,
+    #[serde(alias = "top-left")]
+    TopLeft,
+    #[serde(alias = "coordinates")]
+    Coordinates { x: i32, y: i32 },
+}
+
 #[derive(PartialEq, Serialize, Deserialize, Clone, Debug)]
 pub struct Window {
     #[serde(default = "default_window_width")]
@@ -34,8 +95,17 @@ pub struct Window {
     pub blur: bool,
     #[serde(rename = "background-image", skip_serializing)]
     pub background_image: Option<ImageProperties>,
+    #[serde(default = "BackgroundFit::default", rename = "background-fit")]
+    pub background_fit: BackgroundFit,
+    #[serde(
+        default = "BackgroundPosition::default",
+        rename = "background-position"
+    )]
+    pub background_position: BackgroundPosition,
     #[serde(default = "Decorations::default")]
     pub decorations: Decorations,
+    #[serde(default = "Option::default")]
+    pub position: Option<WindowPosition>,
 }
 
 impl Default for Window {
@@ -46,8 +116,11 @@ impl Default for Window {
             mode: WindowMode::default(),
             opacity: default_opacity(),
             background_image: None,
+            background_fit: BackgroundFit::default(),
+            background_position: BackgroundPosition::default(),
             decorations: Decorations::default(),
             blur: false,
+            position: None,
         }
     }
 }
@@ -56,4 +129,44 @@ impl Window {
     pub fn is_fullscreen(&self) -> bool {
         self.mode == WindowMode::Fullscreen
     }
+
+    pub fn is_borderless_fullscreen(&self) -> bool {
+        self.mode == WindowMode::BorderlessFullscreen
+    }
+
+    // Returns the top-left corner of the window for a monitor of the
+    // given size, Centered is clamped so the window never starts offscreen
+    pub fn resolved_position(&self, monitor_size: (i32, i32)) -> Option<(i32, i32)> {
+        match self.position? {
+            WindowPosition::Centered => Some((
+                ((monitor_size.0 - self.width) / 2).max(0),
+                ((monitor_size.1 - self.height) / 2).max(0),
+            )),
+            WindowPosition::TopLeft => Some((0, 0)),
+            WindowPosition::Coordinates { x, y } => Some((x, y)),
+        }
+    }
+
+    // Clamps opacity to 0.0..=1.0 and disables blur for opaque windows,
+    // since there is nothing behind the window to blur
+    pub fn normalized(mut self) -> Self {
+        if !(0.0..=1.0).contains(&self.opacity) {
+            let opacity = if self.opacity.is_nan() {
+                default_opacity()
+            } else {
+                self.opacity.clamp(0.0, 1.0)
+            };
+            warn!(
+                "window opacity {} is out of range 0.0..=1.0, using {}",
+                self.opacity, opacity
+            );
+            self.opacity = opacity;
+        }
+
+        if self.opacity >= 1.0 {
+            self.blur = false;
+        }
+
+        self
+    }
 }
@@ -206,6 +206,29 @@ impl Compositor {
                                 &cursor_color,
                             );
                         }
+                        SugarCursor::HollowBlock(cursor_color) => {
+                            let border = 1.0;
+                            let bottom = style.topline + style.line_height - border;
+                            let right = rect.x + rect.width - border;
+                            for border_rect in [
+                                Rect::new(rect.x, style.topline, rect.width, border),
+                                Rect::new(rect.x, bottom, rect.width, border),
+                                Rect::new(
+                                    rect.x,
+                                    style.topline,
+                                    border,
+                                    style.line_height,
+                                ),
+                                Rect::new(
+                                    right,
+                                    style.topline,
+                                    border,
+                                    style.line_height,
+                                ),
+                            ] {
+                                self.batches.add_rect(&border_rect, depth, &cursor_color);
+                            }
+                        }
                         SugarCursor::Caret(cursor_color) => {
                             self.batches.add_rect(
                                 &Rect::new(rect.x, style.topline, 3.0, style.line_height),
@@ -252,5 +275,25 @@ impl Compositor {
                 );
             }
         }
+        if let Some(strikethrough) = style.strikethrough {
+            let size = strikethrough.size.round().max(1.);
+            let sy = (style.baseline - strikethrough.offset).round();
+            if strikethrough.is_doubled {
+                // Keep one stroke of space between both lines.
+                for y in [sy - size, sy + size] {
+                    self.draw_rect(
+                        Rect::new(x, y, rect.width, size),
+                        depth,
+                        &strikethrough.color,
+                    );
+                }
+            } else {
+                self.draw_rect(
+                    Rect::new(x, sy, rect.width, size),
+                    depth,
+                    &strikethrough.color,
+                );
+            }
+        }
     }
 }
@@ -13,7 +13,7 @@ use compositor::{
 };
 use fnv::FnvHashMap;
 use std::{borrow::Cow, mem};
-use text::{Glyph, TextRunStyle, UnderlineStyle};
+use text::{Glyph, StrikethroughStyle, TextRunStyle, UnderlineStyle};
 use wgpu::util::DeviceExt;
 use wgpu::Texture;
 
@@ -673,7 +673,8 @@ fn draw_layout(
                     glyphs.push(Glyph { id: glyph.id, x, y });
                 }
             }
-            let color = run.color();
+            let mut color = run.color();
+            color[3] *= run.alpha();
 
             let line_height = line.ascent() + line.descent() + line.leading();
             let style = TextRunStyle {
@@ -682,9 +683,11 @@ fn draw_layout(
                 font_size: run.font_size(),
                 color,
                 cursor: run.cursor(),
-                background_color: run.background_color(),
+                // Runs without width, e.g. zero width marks, paint nothing.
+                background_color: run.background_color().filter(|_| run.advance() > 0.),
                 baseline: py,
-                topline: py - line.ascent(),
+                // Fill from the top of the line so leading doesn't leave gaps.
+                topline: py - line.ascent() - line.leading() * 0.5,
                 line_height,
                 advance: px - run_x,
                 underline: if run.underline() {
@@ -696,6 +699,16 @@ fn draw_layout(
                 } else {
                     None
                 },
+                strikethrough: run.strikethrough().map(|info| StrikethroughStyle {
+                    offset: run.strikeout_offset(),
+                    size: if info.size > 0. {
+                        info.size
+                    } else {
+                        run.strikeout_size()
+                    },
+                    is_doubled: info.is_doubled,
+                    color,
+                }),
             };
 
             comp.draw_glyphs(
@@ -734,7 +747,8 @@ fn fetch_dimensions(
                     glyphs.push(Glyph { id: glyph.id, x, y });
                 }
             }
-            let color = run.color();
+            let mut color = run.color();
+            color[3] *= run.alpha();
 
             let style = TextRunStyle {
                 font: font_library[*font].as_ref(),
@@ -744,10 +758,11 @@ fn fetch_dimensions(
                 cursor: run.cursor(),
                 background_color: None,
                 baseline: py,
-                topline: py - line.ascent(),
+                topline: py - line.ascent() - line.leading() * 0.5,
                 line_height,
                 advance: px - run_x,
                 underline: None,
+                strikethrough: None,
             };
 
             if style.advance > 0. && line_height > 0. {
@@ -35,6 +35,8 @@ pub struct TextRunStyle<'a> {
     pub advance: f32,
     /// Underline style.
     pub underline: Option<UnderlineStyle>,
+    /// Strikethrough style.
+    pub strikethrough: Option<StrikethroughStyle>,
     /// Cursor style.
     pub cursor: SugarCursor,
 }
@@ -50,6 +52,19 @@ pub struct UnderlineStyle {
     pub color: [f32; 4],
 }
 
+/// Strikethrough decoration style.
+#[derive(Copy, Clone)]
+pub struct StrikethroughStyle {
+    /// Offset of the strikethrough stroke from the baseline.
+    pub offset: f32,
+    /// Thickness of the strikethrough stroke.
+    pub size: f32,
+    /// Draws two strokes instead of one.
+    pub is_doubled: bool,
+    /// Color of the strikethrough.
+    pub color: [f32; 4],
+}
+
 /// Positioned glyph in a text run.
 #[derive(Copy, Clone)]
 pub struct Glyph {
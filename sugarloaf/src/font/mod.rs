@@ -18,7 +18,7 @@ use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
 use swash::proxy::CharmapProxy;
 use swash::text::cluster::{CharCluster, Status};
-use swash::{Attributes, CacheKey, Charmap, FontRef, Synthesis};
+use swash::{Attributes, CacheKey, Charmap, FontRef, StringId, Synthesis};
 
 pub use swash::{Style, Weight};
 
@@ -88,8 +88,12 @@ impl FontContext {
         let is_cache_key_empty = cache_key.is_empty();
 
         if !is_cache_key_empty {
-            if let Some(cached_font_id) = self.cache.get(&cache_key) {
-                let cached_font_id = *cached_font_id;
+            if let Some(&cached_font_id) = self.cache.get(&cache_key) {
+                if cached_font_id >= library.len() {
+                    // Cached for a font library that had more fonts.
+                    self.cache.remove(&cache_key);
+                    return self.map_cluster(cluster, synth, library, fonts_to_load);
+                }
                 let charmap = library[cached_font_id]
                     .charmap_proxy()
                     .materialize(&library[cached_font_id].as_ref());
@@ -102,6 +106,34 @@ impl FontContext {
             }
         }
 
+        // Clusters carrying the emoji presentation selector (VS16) prefer
+        // the emoji font over text fonts that also cover the character.
+        if cluster.chars().iter().any(|c| c.ch == '\u{FE0F}') {
+            for (id, font_source) in library.inner.iter().enumerate() {
+                match font_source {
+                    FontSource::Data(font_data) if font_data.is_emoji => {
+                        let charmap =
+                            font_data.charmap_proxy().materialize(&font_data.as_ref());
+                        if cluster.map(|ch| charmap.map(ch)) != Status::Discard {
+                            *synth = font_data.synth;
+                            if !is_cache_key_empty {
+                                self.cache.insert(cache_key, id);
+                            }
+                            return Some(id);
+                        }
+                    }
+                    FontSource::Extension(font_data_extension)
+                        if font_data_extension.is_emoji =>
+                    {
+                        // Needs to be loaded before it can be used
+                        fonts_to_load.push((id, font_data_extension.path.clone()));
+                        return None;
+                    }
+                    _ => {}
+                }
+            }
+        }
+
         if let Some(found_font_id) = self.lookup_for_font_match(cluster, synth, library) {
             if !is_cache_key_empty {
                 self.cache.insert(cache_key, found_font_id);
@@ -171,6 +203,19 @@ impl FontLibrary {
     }
 }
 
+impl FontLibrary {
+    /// Returns the family name of the font at `index`, the value of
+    /// `Run::font`. Fonts that are not loaded yet have no name.
+    pub fn family_name(&self, index: usize) -> Option<String> {
+        let library = self.inner.read().unwrap();
+        match library.inner.get(index)? {
+            FontSource::Data(font_data) => font_data.family_name(),
+            FontSource::Standard => library.standard.family_name(),
+            FontSource::Extension(_) => None,
+        }
+    }
+}
+
 impl Default for FontLibrary {
     fn default() -> Self {
         let mut font_library = FontLibraryData::default();
@@ -531,6 +576,15 @@ impl FontData {
         self.charmap_proxy
     }
 
+    /// Returns the family name from the font naming table.
+    #[inline]
+    pub fn family_name(&self) -> Option<String> {
+        self.as_ref()
+            .localized_strings()
+            .find_by_id(StringId::Family, None)
+            .map(|name| name.chars().collect())
+    }
+
     // Create the transient font reference for accessing this crate's
     // functionality.
     #[inline]
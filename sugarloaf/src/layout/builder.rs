@@ -8,36 +8,69 @@
 
 //! Render data builder.
 
-// use super::bidi::*;
+use super::bidi::{BidiDirection, BidiResolver};
 use super::builder_data::*;
+use super::content::Content;
 use super::span_style::*;
-use super::MAX_ID;
-use crate::font::{FontContext, FontLibrary, FontLibraryData};
-use crate::layout::render_data::{RenderData, RunCacheEntry};
+use super::{LayoutDirection, MAX_ID};
+use crate::font::{FontContext, FontLibrary, FontLibraryData, FONT_ID_REGULAR};
+use crate::layout::layout_data::RunSynthesis;
+use crate::layout::render_data::{font_id_for_attrs, RenderData, RunCacheEntry};
 use std::collections::HashMap;
+use std::ops::Range;
 use std::path::PathBuf;
 use swash::shape::{self, ShapeContext};
-use swash::text::cluster::{CharCluster, CharInfo, Parser, Token};
+use swash::text::cluster::{Boundary, CharCluster, CharInfo, Parser, Token};
 use swash::text::{analyze, Language, Script};
-use swash::{Setting, Synthesis};
+use swash::{Attributes, Setting, Synthesis};
+
+/// Default number of lines kept by the run cache.
+pub const DEFAULT_RUN_CACHE_CAPACITY: usize = 1024;
+
+/// Hit and miss counters of the run cache, see `LayoutContext::take_stats`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Lines laid out from the run cache.
+    pub run_hits: u64,
+    /// Lines with a hash that had to be shaped.
+    pub run_misses: u64,
+}
 
 pub struct RunCache {
     inner: HashMap<u64, RunCacheEntry>,
+    capacity: usize,
+    stats: CacheStats,
+    /// Scale of the current builder. Line hashes already cover font ids
+    /// and sizes of the fragments but not the builder scale, so entries
+    /// are only reused at the scale they were shaped at.
+    scale: f32,
 }
 
 impl RunCache {
     #[inline]
-    fn new() -> Self {
+    fn new(capacity: usize) -> Self {
         Self {
             inner: HashMap::default(),
+            capacity,
+            stats: CacheStats::default(),
+            scale: 0.,
         }
     }
 
+    /// Returns the entry of `line_hash` if it was shaped at `scale`.
+    #[inline]
+    fn get(&self, line_hash: u64, scale: f32) -> Option<&RunCacheEntry> {
+        self.inner
+            .get(&line_hash)
+            .filter(|entry| entry.scale == scale)
+    }
+
     #[inline]
-    fn insert(&mut self, line_hash: u64, data: RunCacheEntry) {
+    fn insert(&mut self, line_hash: u64, mut data: RunCacheEntry) {
         if data.runs.is_empty() {
             return;
         }
+        data.scale = self.scale;
 
         if let Some(line) = self.inner.get_mut(&line_hash) {
             *line = data;
@@ -48,37 +81,200 @@ impl RunCache {
 
     #[inline]
     fn clear_on_max_capacity(&mut self) {
-        if self.inner.len() > 1024 {
+        if self.inner.len() > self.capacity {
             self.inner.clear();
         }
     }
 }
 
+/// Metrics of a single terminal cell.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CellMetrics {
+    pub advance: f32,
+    pub ascent: f32,
+    pub descent: f32,
+    pub leading: f32,
+}
+
 /// Context for paragraph layout.
 pub struct LayoutContext {
     fcx: FontContext,
     fonts: FontLibrary,
-    // bidi: BidiResolver,
+    bidi: BidiResolver,
     scx: ShapeContext,
     state: BuilderState,
     cache: RunCache,
+    cell_metrics: HashMap<(u32, u32), CellMetrics>,
     fonts_to_load: Vec<(usize, PathBuf)>,
 }
 
 impl LayoutContext {
     /// Creates a new layout context with the specified font library.
     pub fn new(font_library: &FontLibrary) -> Self {
+        Self::with_cache_capacity(font_library, DEFAULT_RUN_CACHE_CAPACITY)
+    }
+
+    /// Creates a new layout context that keeps shaped runs for up to
+    /// `run_capacity` lines before the cache is flushed.
+    ///
+    /// Every cached line holds its clusters and glyphs, so a larger
+    /// capacity trades memory for less reshaping. Windows with many rows
+    /// should use a capacity of a few screens worth of lines to avoid
+    /// reshaping on scroll.
+    pub fn with_cache_capacity(font_library: &FontLibrary, run_capacity: usize) -> Self {
         Self {
             fonts: font_library.clone(),
             fcx: FontContext::default(),
-            // bidi: BidiResolver::new(),
+            bidi: BidiResolver::new(),
             scx: ShapeContext::new(),
             state: BuilderState::new(),
-            cache: RunCache::new(),
+            cache: RunCache::new(run_capacity),
+            cell_metrics: HashMap::default(),
             fonts_to_load: vec![],
         }
     }
 
+    /// Returns the metrics of a cell for the regular font, shaping a
+    /// single space the first time a font size and scale are requested.
+    pub fn cell_metrics(&mut self, font_size: f32, scale: f32) -> CellMetrics {
+        let key = (font_size.to_bits(), scale.to_bits());
+        if let Some(metrics) = self.cell_metrics.get(&key) {
+            return *metrics;
+        }
+
+        let mut render_data = RenderData::new();
+        let mut lb = self.builder(Direction::LeftToRight, None, scale);
+        lb.add_text(
+            " ",
+            FragmentStyle {
+                font_size,
+                ..FragmentStyle::default()
+            },
+        );
+        lb.build_into(&mut render_data);
+        render_data
+            .break_lines()
+            .break_without_advance_or_alignment();
+
+        let metrics = render_data
+            .lines()
+            .next()
+            .map(|line| CellMetrics {
+                advance: line
+                    .runs()
+                    .flat_map(|run| run.clusters())
+                    .next()
+                    .map(|cluster| cluster.advance())
+                    .unwrap_or(0.),
+                ascent: line.ascent(),
+                descent: line.descent(),
+                leading: line.leading(),
+            })
+            .unwrap_or_default();
+        self.cell_metrics.insert(key, metrics);
+        metrics
+    }
+
+    /// Returns the total advance of `text` laid out on a single line,
+    /// e.g. to size a tab title. Text mixing scripts is split into
+    /// several runs whose advances are summed.
+    pub fn measure(&mut self, text: &str, style: FragmentStyle) -> f32 {
+        let mut render_data = RenderData::new();
+        let mut lb = self.builder(Direction::LeftToRight, None, 1.);
+        lb.add_text(text, style);
+        lb.build_into(&mut render_data);
+        render_data.data.runs.iter().map(|run| run.advance).sum()
+    }
+
+    /// Sets the amount of cells between tab stops, 0 renders tabs as
+    /// regular glyphs.
+    #[inline]
+    pub fn set_tab_width(&mut self, tab_width: u32) {
+        self.state.tab_width = tab_width;
+    }
+
+    /// Sets how tab characters are drawn. Indicators are taken from the
+    /// font of the run and skipped when the font doesn't have them.
+    #[inline]
+    pub fn set_tab_render(&mut self, tab_render: TabRender) {
+        self.state.tab_render = tab_render;
+    }
+
+    /// Sets how control characters other than tabs and line breaks are
+    /// laid out. Every character shown for a control character maps to
+    /// its offset.
+    #[inline]
+    pub fn set_control_char_mode(&mut self, control_char_mode: ControlCharMode) {
+        if self.state.control_char_mode != control_char_mode {
+            self.state.control_char_mode = control_char_mode;
+            // Cached runs were shaped from the previous characters.
+            self.cache.inner.clear();
+        }
+    }
+
+    /// Snaps the advance of every cluster to a whole number of cells of
+    /// `cell_advance`, so wide glyphs and ligatures keep the grid aligned.
+    #[inline]
+    pub fn set_monospace_snap(&mut self, monospace_snap: bool, cell_advance: f32) {
+        self.state.monospace_snap = monospace_snap;
+        self.state.cell_advance = cell_advance;
+    }
+
+    /// Sets a minimum line height in pixels. Shorter lines are padded
+    /// and keep the text centered, taller lines are left untouched.
+    #[inline]
+    pub fn set_min_line_height(&mut self, min_line_height: Option<f32>) {
+        self.state.min_line_height = min_line_height;
+    }
+
+    /// Scales the underline and strikeout thickness taken from the font,
+    /// e.g. for thin fonts on high density displays. Scaled thicknesses
+    /// are never thinner than a pixel.
+    #[inline]
+    pub fn set_decoration_thickness(&mut self, decoration_thickness: f32) {
+        self.state.decoration_thickness = Some(decoration_thickness);
+    }
+
+    /// Moves every underline by `underline_offset_adjust` pixels, e.g.
+    /// below the descenders of fonts whose underline crosses them.
+    #[inline]
+    pub fn set_underline_offset_adjust(&mut self, underline_offset_adjust: f32) {
+        self.state.underline_offset_adjust = underline_offset_adjust;
+    }
+
+    /// Sets the color reported by `Cluster::color` for clusters that no
+    /// loaded font can render, e.g. red to spot missing glyphs.
+    #[inline]
+    pub fn set_notdef_color(&mut self, notdef_color: Option<[f32; 4]>) {
+        self.state.notdef_color = notdef_color;
+    }
+
+    /// Sets the direction lines advance and stack in. Vertical layouts
+    /// shape with the `vert` and `vrt2` features, so glyphs like CJK
+    /// punctuation use their vertical forms.
+    #[inline]
+    pub fn set_layout_direction(&mut self, layout_direction: LayoutDirection) {
+        if self.state.layout_direction != layout_direction {
+            self.state.layout_direction = layout_direction;
+            // Cached runs were shaped with the previous features.
+            self.cache.inner.clear();
+        }
+    }
+
+    /// Updates the amount of lines kept by the run cache.
+    #[inline]
+    pub fn set_cache_capacity(&mut self, run_capacity: usize) {
+        self.cache.capacity = run_capacity;
+    }
+
+    /// Grows the run cache to keep at least two screens of `rows` lines,
+    /// so redrawing a tall window doesn't flush the cache every frame.
+    /// Cached lines are kept, call it whenever the window is resized.
+    #[inline]
+    pub fn ensure_cache_capacity(&mut self, rows: usize) {
+        self.cache.capacity = self.cache.capacity.max(rows * 2);
+    }
+
     #[inline]
     pub fn font_library(&self) -> &FontLibrary {
         &self.fonts
@@ -89,18 +285,19 @@ impl LayoutContext {
     #[inline]
     pub fn builder(
         &mut self,
-        _direction: Direction,
+        direction: Direction,
         _language: Option<Language>,
         scale: f32,
     ) -> ParagraphBuilder {
         self.state.clear();
         self.state.begin();
         self.state.scale = scale;
+        self.cache.scale = scale;
         ParagraphBuilder {
             fcx: &mut self.fcx,
-            // bidi: &mut self.bidi,
-            // needs_bidi: false,
-            // dir: direction,
+            bidi: &mut self.bidi,
+            needs_bidi: false,
+            dir: direction,
             fonts: &self.fonts,
             scx: &mut self.scx,
             s: &mut self.state,
@@ -114,15 +311,93 @@ impl LayoutContext {
     pub fn clear_cache(&mut self) {
         self.cache.inner.clear();
     }
+
+    /// Removes the cached layout of the line with the given hash, so it's
+    /// shaped again the next time it's built.
+    #[inline]
+    pub fn invalidate_line(&mut self, hash: u64) {
+        self.cache.inner.remove(&hash);
+    }
+
+    /// Removes the cached layout of every line in `hashes`.
+    #[inline]
+    pub fn invalidate_lines(&mut self, hashes: &[u64]) {
+        for hash in hashes {
+            self.cache.inner.remove(hash);
+        }
+    }
+
+    /// Returns the cache counters accumulated since the last call and
+    /// resets them.
+    #[inline]
+    pub fn take_stats(&mut self) -> CacheStats {
+        std::mem::take(&mut self.cache.stats)
+    }
+
+    /// Shapes the lines of `content` that are not in the run cache yet,
+    /// e.g. lines about to be scrolled into view, so the next layout
+    /// finds them cached. Lines without a hash are skipped since they are
+    /// never cached. `scale` must match the one of the next builder.
+    pub fn prewarm(&mut self, content: &Content, scale: f32) {
+        let missing: Vec<usize> = (0..content.current_line + 1)
+            .filter(|&line| {
+                let hash = content.fragments[line].hash();
+                hash != 0 && self.cache.get(hash, scale).is_none()
+            })
+            .collect();
+        if missing.is_empty() {
+            return;
+        }
+
+        let mut render_data = RenderData::new();
+        let mut lb = self.builder(Direction::LeftToRight, None, scale);
+        for line in missing {
+            content.layout_line(&mut lb, line);
+            lb.new_line();
+        }
+        lb.build_into(&mut render_data);
+    }
+
+    /// Sets the font features applied to every fragment. Features of a
+    /// fragment take precedence over these.
+    #[inline]
+    pub fn set_font_features(&mut self, features: &[Setting<u16>]) {
+        self.state.global_features.clear();
+        self.state.global_features.extend_from_slice(features);
+        self.cache.inner.clear();
+    }
+
+    /// Enables or disables ligatures, e.g. to display code as typed.
+    /// Disabling turns off `liga` and `calt` over any fragment features.
+    #[inline]
+    pub fn set_ligatures(&mut self, ligatures: bool) {
+        if self.state.ligatures != ligatures {
+            self.state.ligatures = ligatures;
+            // Cached runs were shaped with the previous features.
+            self.cache.inner.clear();
+        }
+    }
+
+    /// Records on each run whether its font was picked by fallback, see
+    /// `Run::is_fallback`. Useful to find out which font served a glyph
+    /// rendered as a missing box.
+    #[inline]
+    pub fn set_fallback_trace(&mut self, fallback_trace: bool) {
+        if self.state.fallback_trace != fallback_trace {
+            self.state.fallback_trace = fallback_trace;
+            // Cached runs carry the previous value.
+            self.cache.inner.clear();
+        }
+    }
 }
 
 /// Builder for computing the layout of a paragraph.
 pub struct ParagraphBuilder<'a> {
     fcx: &'a mut FontContext,
-    // bidi: &'a mut BidiResolver,
+    bidi: &'a mut BidiResolver,
     fonts: &'a FontLibrary,
-    // needs_bidi: bool,
-    // dir: Direction,
+    needs_bidi: bool,
+    dir: Direction,
     scx: &'a mut ShapeContext,
     s: &'a mut BuilderState,
     last_offset: u32,
@@ -177,8 +452,40 @@ impl<'a> ParagraphBuilder<'a> {
         self.s.new_line();
     }
 
+    /// Registers a list of font features for this paragraph, returning
+    /// the key to use as `FragmentStyle::font_features`.
+    #[inline]
+    pub fn add_font_features(
+        &mut self,
+        features: impl IntoIterator<Item = Setting<u16>>,
+    ) -> FontSettingKey {
+        self.s.features.add(features.into_iter())
+    }
+
+    /// Registers a list of font variations for this paragraph, returning
+    /// the key to use as `FragmentStyle::font_vars`.
+    #[inline]
+    pub fn add_font_variations(
+        &mut self,
+        variations: impl IntoIterator<Item = Setting<f32>>,
+    ) -> FontSettingKey {
+        self.s.vars.add(variations.into_iter())
+    }
+
     /// Adds a text fragment to the paragraph.
-    pub fn add_text(&mut self, text: &str, mut style: FragmentStyle) -> Option<()> {
+    #[inline]
+    pub fn add_text(&mut self, text: &str, style: FragmentStyle) -> Option<()> {
+        self.push_text(text, style, true)
+    }
+
+    /// Adds a text fragment, extending the previous fragment when `merge`
+    /// is set and both have the same style.
+    fn push_text(
+        &mut self,
+        text: &str,
+        mut style: FragmentStyle,
+        merge: bool,
+    ) -> Option<()> {
         let current_line = self.s.current_line();
         let line = &mut self.s.lines[current_line];
         let id = line.text.frags.len();
@@ -189,8 +496,21 @@ impl<'a> ParagraphBuilder<'a> {
         let mut offset = self.last_offset;
         // let mut offset = self.last_offset;
         style.font_size *= self.s.scale;
-        line.styles.push(style);
-        let span_id = line.styles.len() - 1;
+        let start = line.text.content.len();
+        // Text following a fragment with the same style extends it, so
+        // splitting text across calls doesn't split runs.
+        let merged_span = line
+            .fragments
+            .last()
+            .filter(|prev| merge && prev.end == start && line.styles[prev.span] == style)
+            .map(|prev| prev.span);
+        let span_id = match merged_span {
+            Some(span) => span,
+            None => {
+                line.styles.push(style);
+                line.styles.len() - 1
+            }
+        };
 
         // if let Some(dir) = style.dir {
         //     const LRI: char = '\u{2066}';
@@ -212,7 +532,6 @@ impl<'a> ParagraphBuilder<'a> {
             }};
         }
 
-        let start = line.text.content.len();
         // match style.text_transform {
         //     TextTransform::Uppercase => {
         //         if let Some(lang) = &style.lang {
@@ -314,8 +633,39 @@ impl<'a> ParagraphBuilder<'a> {
         //     }
         // }
 
+        let control_char_mode = self.s.control_char_mode;
         for ch in text.chars() {
-            push_char!(ch);
+            // Tabs expand to tab stops and line breaks must stay mandatory
+            // breaks, e.g. the newline `ContentBuilder::break_line` adds.
+            let keep = !ch.is_control()
+                || matches!(ch, '\t' | '\n' | '\r' | '\u{2028}' | '\u{2029}');
+            if keep || control_char_mode == ControlCharMode::Keep {
+                push_char!(ch);
+                continue;
+            }
+            match control_char_mode {
+                // Hidden characters only advance the source offset.
+                ControlCharMode::Keep | ControlCharMode::Hide => {}
+                ControlCharMode::Caret => {
+                    // ^@ to ^_ for C0, ^? for DEL, C1 codes have no
+                    // caret form and are shown as replacements.
+                    let caret = match ch as u32 {
+                        code @ 0..=0x1f => Some(char::from(code as u8 + 0x40)),
+                        0x7f => Some('?'),
+                        _ => None,
+                    };
+                    line.text.content.push('^');
+                    line.text.offsets.push(offset);
+                    line.text.content.push(caret.unwrap_or('\u{fffd}'));
+                    line.text.offsets.push(offset);
+                }
+                ControlCharMode::Replacement => {
+                    line.text.content.push('\u{fffd}');
+                    line.text.offsets.push(offset);
+                }
+            }
+            // Offsets keep pointing into the source text.
+            offset += ch.len_utf8() as u32;
         }
 
         let end = line.text.content.len();
@@ -344,6 +694,13 @@ impl<'a> ParagraphBuilder<'a> {
         for _ in 0..len {
             line.text.spans.push(span_id);
         }
+        if merged_span.is_some() {
+            if let Some(prev_frag) = line.fragments.last_mut() {
+                prev_frag.end = end;
+            }
+            self.last_offset = offset;
+            return Some(());
+        }
         line.fragments.push(FragmentData {
             span: span_id,
             break_shaping,
@@ -359,9 +716,26 @@ impl<'a> ParagraphBuilder<'a> {
     }
 
     /// Consumes the builder and fills the specified paragraph with the result.
-    pub fn build_into(mut self, render_data: &mut RenderData) {
-        self.resolve(render_data);
-        render_data.finish();
+    pub fn build_into(self, render_data: &mut RenderData) {
+        self.build_into_range(render_data, 0..usize::MAX);
+    }
+
+    /// Consumes the builder and lays out only the lines within `lines`
+    /// into `render_data`, e.g. the rows of a viewport over a long
+    /// scrollback. Lines outside the range are neither shaped nor read
+    /// from the cache, so the first line of the range is laid out at the
+    /// top of the paragraph.
+    pub fn build_into_range(mut self, render_data: &mut RenderData, lines: Range<usize>) {
+        self.resolve(render_data, &lines);
+        render_data.data.min_line_height = self.s.min_line_height;
+        render_data.data.decoration_thickness = self.s.decoration_thickness;
+        render_data.data.underline_offset_adjust = self.s.underline_offset_adjust;
+        render_data.data.notdef_color = self.s.notdef_color;
+        render_data.data.layout_direction = self.s.layout_direction;
+        // The trailing space from `resolve` is only shaped with the last line.
+        if lines.contains(&(self.s.lines.len() - 1)) {
+            render_data.finish();
+        }
     }
 
     /// Consumes the builder and returns the resulting paragraph.
@@ -380,22 +754,28 @@ impl<'a> ParagraphBuilder<'a> {
         current_line: usize,
     ) -> bool {
         if let Some(line_hash) = self.s.lines[current_line].hash {
-            if let Some(data) = self.cache.inner.get(&line_hash) {
-                render_data.push_run_from_cached_line(data, current_line as u32);
+            if let Some(data) = self.cache.get(line_hash, self.s.scale) {
+                if render_data.push_run_from_cached_line(data, current_line as u32) {
+                    self.cache.stats.run_hits += 1;
 
-                return true;
+                    return true;
+                }
+                // Reshape the line instead of following broken indexes.
+                self.cache.inner.remove(&line_hash);
             }
+            self.cache.stats.run_misses += 1;
         }
 
         false
     }
 
-    fn resolve(&mut self, render_data: &mut RenderData) {
+    fn resolve(&mut self, render_data: &mut RenderData, lines: &Range<usize>) {
         // Bit of a hack: add a single trailing space fragment to account for
         // empty paragraphs and to force an extra break if the paragraph ends
         // in a newline.
 
-        self.add_text(" ", FragmentStyle::default());
+        // Never merged, so fragments only cover the text that was added.
+        self.push_text(" ", FragmentStyle::default(), false);
         // for _ in 0..self.dir_depth {
         // const PDI: char = '\u{2069}';
         // self.push_char(PDI);
@@ -404,11 +784,14 @@ impl<'a> ParagraphBuilder<'a> {
         // Cache needs to be cleaned before build lines
         self.cache.clear_on_max_capacity();
 
-        for line_number in 0..self.s.lines.len() {
+        let len = self.s.lines.len();
+        for line_number in lines.start.min(len)..lines.end.min(len) {
             // In case should render only requested lines
             // and the line number isn't part of the requested then process from cache
             // if render_specific_lines && !lines_to_render.contains(&line_number) {
-            if self.process_from_cache(render_data, line_number) {
+            let from_cache = self.process_from_cache(render_data, line_number);
+            render_data.line_from_cache.push(from_cache);
+            if from_cache {
                 continue;
             }
 
@@ -417,25 +800,22 @@ impl<'a> ParagraphBuilder<'a> {
             for (props, boundary) in analysis.by_ref() {
                 line.text.info.push(CharInfo::new(props, boundary));
             }
-            // if analysis.needs_bidi_resolution() || self.dir != Direction::LeftToRight {
-            //     let dir = match self.dir {
-            //         Direction::Auto => None,
-            //         Direction::LeftToRight => Some(BidiDirection::LeftToRight),
-            //         Direction::RightToLeft => Some(BidiDirection::RightToLeft),
-            //     };
-            //     self.bidi.resolve_with_types(
-            //         &self.s.lines[line_number].text.content,
-            //         self.s.lines[line_number]
-            //             .text
-            //             .info
-            //             .iter()
-            //             .map(|i| i.bidi_class()),
-            //         dir,
-            //     );
-            //     if !self.needs_bidi {
-            //         self.needs_bidi = true;
-            //     }
-            // }
+            // Levels are resolved per line since every line is
+            // laid out as its own paragraph.
+            self.needs_bidi = false;
+            if analysis.needs_bidi_resolution() || self.dir != Direction::LeftToRight {
+                let dir = match self.dir {
+                    Direction::Auto => None,
+                    Direction::LeftToRight => Some(BidiDirection::LeftToRight),
+                    Direction::RightToLeft => Some(BidiDirection::RightToLeft),
+                };
+                self.bidi.resolve_with_types(
+                    &line.text.content,
+                    line.text.info.iter().map(|i| i.bidi_class()),
+                    dir,
+                );
+                self.needs_bidi = true;
+            }
 
             self.itemize(line_number);
             self.shape(render_data, line_number);
@@ -456,10 +836,28 @@ impl<'a> ParagraphBuilder<'a> {
             *render_data = RenderData::default();
             self.last_offset = 0;
 
-            return self.resolve(render_data);
+            return self.resolve(render_data, lines);
         };
 
+        if self.s.monospace_snap && self.s.cell_advance > 0. {
+            render_data.apply_monospace_snap(self.s.cell_advance);
+        }
         render_data.apply_spacing();
+
+        let font_library = { &self.fonts.inner.read().unwrap() };
+        render_data.apply_tab_stops(self.s.tab_width, |run| {
+            // Tab stops are measured in cells of the run font.
+            let font = font_library[run.font].as_ref();
+            let space = font.charmap().map(' ');
+            font.glyph_metrics(&[]).scale(run.size).advance_width(space)
+        });
+        if let Some(indicator) = self.s.tab_render.indicator() {
+            render_data.apply_tab_indicator(|run| {
+                let id = font_library[run.font].as_ref().charmap().map(indicator);
+                // Keep the tab glyph when the font lacks the indicator.
+                (id != 0).then_some(id)
+            });
+        }
     }
 
     fn itemize(&mut self, line_number: usize) {
@@ -475,14 +873,14 @@ impl<'a> ParagraphBuilder<'a> {
             .map(|i| i.script())
             .find(|s| real_script(*s))
             .unwrap_or(Script::Latin);
-        // let levels = self.bidi.levels();
+        let levels = self.bidi.levels();
+        let needs_bidi = self.needs_bidi;
         let mut last_frag = line.fragments.first().unwrap();
-        // let mut last_level = if self.needs_bidi {
-        //     levels[last_frag.start]
-        // } else {
-        //     0
-        // };
-        let last_level = 0;
+        let mut last_level = if needs_bidi {
+            levels[last_frag.start]
+        } else {
+            0
+        };
         let mut last_features = last_frag.features;
         let mut last_vars = last_frag.vars;
         let mut item = ItemData {
@@ -505,34 +903,6 @@ impl<'a> ParagraphBuilder<'a> {
                 }
             };
         }
-        // if self.needs_bidi {
-        //     for frag in &line.fragments {
-        //         if frag.break_shaping || frag.start != last_frag.end {
-        //             push_item!();
-        //             item.start = frag.start;
-        //             item.end = frag.start;
-        //         }
-        //         last_frag = frag;
-        //         last_features = frag.features;
-        //         last_vars = frag.vars;
-        //         let range = frag.start..frag.end;
-        //         for (&props, &level) in
-        //             line.text.info[range.clone()].iter().zip(&levels[range])
-        //         {
-        //             let script = props.script();
-        //             let real = real_script(script);
-        //             if (script != last_script && real) || level != last_level {
-        //                 //item.end += 1;
-        //                 push_item!();
-        //                 if real {
-        //                     last_script = script;
-        //                 }
-        //                 last_level = level;
-        //             }
-        //             item.end += 1;
-        //         }
-        //     }
-        // } else {
         for frag in &line.fragments {
             if frag.break_shaping || frag.start != last_frag.end {
                 push_item!();
@@ -542,22 +912,22 @@ impl<'a> ParagraphBuilder<'a> {
             last_frag = frag;
             last_features = frag.features;
             last_vars = frag.vars;
-            let range = frag.start..frag.end;
-            for &props in &line.text.info[range] {
-                let script = props.script();
+            for index in frag.start..frag.end {
+                let script = line.text.info[index].script();
                 let real = real_script(script);
-                if script != last_script && real {
-                    //item.end += 1;
+                let level = if needs_bidi { levels[index] } else { 0 };
+                // Common and inherited scripts (spaces, punctuation,
+                // combining marks) continue the previous real script.
+                if (script != last_script && real) || level != last_level {
                     push_item!();
                     if real {
                         last_script = script;
                     }
-                } else {
-                    item.end += 1;
+                    last_level = level;
                 }
+                item.end += 1;
             }
         }
-        // }
         push_item!();
     }
 
@@ -600,6 +970,40 @@ fn real_script(script: Script) -> bool {
     script != Script::Common && script != Script::Inherited && script != Script::Unknown
 }
 
+/// Returns true if `ch` has a text and an emoji presentation to pick
+/// from and isn't followed by a selector already. ASCII digits, `#` and
+/// `*` have the Emoji property but only become emoji as keycaps.
+#[inline]
+fn needs_presentation_selector(ch: char, next: Option<char>) -> bool {
+    use swash::text::Codepoint;
+    !ch.is_ascii()
+        && ch.properties().is_emoji()
+        && !matches!(next, Some('\u{FE0E}' | '\u{FE0F}'))
+}
+
+/// Follows `token` with the variation selector requested by the style,
+/// see `needs_presentation_selector`. The selector has no length so
+/// cluster ranges keep covering only the source text.
+#[inline]
+fn with_presentation_selector(
+    token: Token,
+    next: Option<char>,
+    style: &FragmentStyle,
+) -> impl Iterator<Item = Token> + Clone {
+    use swash::text::Codepoint;
+    let selector = style
+        .presentation_selector()
+        .filter(|_| needs_presentation_selector(token.ch, next))
+        .map(|selector| Token {
+            ch: selector,
+            offset: token.offset + token.len as u32,
+            len: 0,
+            info: CharInfo::new(selector.properties(), Boundary::None),
+            data: token.data,
+        });
+    core::iter::once(token).chain(selector)
+}
+
 struct ShapeState<'a> {
     state: &'a BuilderState,
     features: &'a [Setting<u16>],
@@ -650,23 +1054,29 @@ fn shape_item(
         size: style.font_size,
     };
 
+    let content = &state.lines[current_line].text.content;
     if item.level & 1 != 0 {
-        let chars = state.lines[current_line].text.content[range.to_owned()]
+        let chars = content[range.to_owned()]
             .iter()
+            .enumerate()
             .zip(&state.lines[current_line].text.offsets[range.to_owned()])
             .zip(&state.lines[current_line].text.spans[range.to_owned()])
             .zip(&state.lines[current_line].text.info[range])
-            .map(|z| {
+            .flat_map(|z| {
                 use swash::text::Codepoint;
-                let (((&ch, &offset), &span_index), &info) = z;
+                let ((((index, &ch), &offset), &span_index), &info) = z;
                 let ch = ch.mirror().unwrap_or(ch);
-                Token {
-                    ch,
-                    offset,
-                    len: ch.len_utf8() as u8,
-                    info,
-                    data: span_index as u32,
-                }
+                with_presentation_selector(
+                    Token {
+                        ch,
+                        offset,
+                        len: ch.len_utf8() as u8,
+                        info,
+                        data: span_index as u32,
+                    },
+                    content.get(item.start + index + 1).copied(),
+                    &state.lines[current_line].styles[span_index],
+                )
             });
 
         let mut parser = Parser::new(item.script, chars);
@@ -694,23 +1104,28 @@ fn shape_item(
             cache.insert(line_hash, render_data.last_cached_run.to_owned());
         }
     } else {
-        let chars = state.lines[current_line].text.content[range.to_owned()]
+        let chars = content[range.to_owned()]
             .iter()
+            .enumerate()
             .zip(&state.lines[current_line].text.offsets[range.to_owned()])
             .zip(&state.lines[current_line].text.spans[range.to_owned()])
             .zip(&state.lines[current_line].text.info[range])
-            .map(|z| {
-                let (((&ch, &offset), &span_index), &info) = z;
+            .flat_map(|z| {
+                let ((((index, &ch), &offset), &span_index), &info) = z;
                 // if current_line == 0 {
                 //     println!("{:?} {:?} {:?}", ch, span_index as u32, state.lines[current_line].styles[span_index]);
                 // }
-                Token {
-                    ch,
-                    offset,
-                    len: ch.len_utf8() as u8,
-                    info,
-                    data: span_index as u32,
-                }
+                with_presentation_selector(
+                    Token {
+                        ch,
+                        offset,
+                        len: ch.len_utf8() as u8,
+                        info,
+                        data: span_index as u32,
+                    },
+                    content.get(item.start + index + 1).copied(),
+                    &state.lines[current_line].styles[span_index],
+                )
             });
 
         let mut parser = Parser::new(item.script, chars);
@@ -740,6 +1155,36 @@ fn shape_item(
     Some(())
 }
 
+/// Synthesis required to draw `span` with the face the renderer picks
+/// for `font_id`, e.g. a fallback font that only ships a regular weight.
+fn run_synthesis(
+    fonts: &FontLibraryData,
+    font_id: usize,
+    span: &FragmentStyle,
+) -> RunSynthesis {
+    let font_id = if font_id == FONT_ID_REGULAR {
+        font_id_for_attrs(span)
+    } else {
+        font_id
+    };
+    if font_id >= fonts.len() {
+        return RunSynthesis::default();
+    }
+    let face = &fonts[font_id];
+    if face.is_emoji {
+        return RunSynthesis::default();
+    }
+
+    let (stretch, weight, style) = span.font_attrs;
+    let synth = face
+        .attributes()
+        .synthesize(Attributes::new(stretch, weight, style));
+    RunSynthesis {
+        embolden: synth.embolden(),
+        skew: synth.skew().unwrap_or(0.),
+    }
+}
+
 #[inline]
 #[allow(clippy::too_many_arguments)]
 fn shape_clusters<I>(
@@ -762,17 +1207,36 @@ where
     }
 
     let current_font_id = state.font_id.unwrap();
+    if current_font_id >= fonts.len() {
+        // The font library changed since the font was picked.
+        log::warn!("Skipping text shaped with missing font {current_font_id}");
+        return false;
+    }
     let mut shaper = scx
         .builder(fonts[current_font_id].as_ref())
         .script(state.script)
         // .language(state.span.lang)
         .direction(dir)
         .size(state.size)
+        .features(state.state.global_features.iter().copied())
         .features(state.features.iter().copied())
+        .features(
+            [("liga", 0), ("calt", 0)]
+                .into_iter()
+                .filter(|_| !state.state.ligatures)
+                .map(Setting::from),
+        )
+        .features(
+            [("vert", 1), ("vrt2", 1)]
+                .into_iter()
+                .filter(|_| state.state.layout_direction.is_vertical())
+                .map(Setting::from),
+        )
         .variations(state.synth.variations().iter().copied())
         .variations(state.vars.iter().copied())
         .build();
 
+    let fallback = state.state.fallback_trace && current_font_id != FONT_ID_REGULAR;
     let mut synth = Synthesis::default();
     loop {
         // for c in cluster.chars().iter() {
@@ -791,6 +1255,8 @@ where
                 state.level,
                 current_line as u32,
                 shaper,
+                fallback,
+                |span| run_synthesis(fonts, current_font_id, span),
             );
             return false;
         }
@@ -817,6 +1283,8 @@ where
                 state.level,
                 current_line as u32,
                 shaper,
+                fallback,
+                |span| run_synthesis(fonts, current_font_id, span),
             );
             state.font_id = next_font;
             state.synth = synth;
@@ -824,3 +1292,881 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_itemize_splits_on_script_boundaries() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let mut render_data = RenderData::new();
+        let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+        lb.add_text("hello مرحبا", FragmentStyle::default());
+        lb.build_into(&mut render_data);
+
+        let items = &lcx.state.lines[0].items;
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[0].script, Script::Latin);
+        assert_eq!((items[0].start, items[0].end), (0, 6));
+        assert_eq!(items[1].script, Script::Arabic);
+        assert_eq!((items[1].start, items[1].end), (6, 11));
+        // Trailing space added by resolve keeps the arabic script
+        // but goes back to the paragraph level.
+        assert_eq!(items[2].script, Script::Arabic);
+        assert_eq!(items[2].level, 0);
+    }
+
+    #[test]
+    fn test_itemize_resolves_bidi_levels() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let mut render_data = RenderData::new();
+        let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+        lb.add_text("abc אבג", FragmentStyle::default());
+        lb.build_into(&mut render_data);
+
+        let levels: Vec<u8> = lcx.state.lines[0]
+            .items
+            .iter()
+            .map(|item| item.level)
+            .collect();
+        assert_eq!(levels, vec![0, 1, 0]);
+        assert_eq!(lcx.state.lines[0].items[1].script, Script::Hebrew);
+    }
+
+    #[test]
+    fn test_add_text_merges_fragments_with_same_style() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let mut render_data = RenderData::new();
+        let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+        lb.add_text("ab", FragmentStyle::default());
+        lb.add_text("cd", FragmentStyle::default());
+        lb.build_into(&mut render_data);
+
+        let line = &lcx.state.lines[0];
+        // The trailing space added by `resolve` keeps its own fragment.
+        assert_eq!(line.fragments.len(), 2);
+        assert_eq!((line.fragments[0].start, line.fragments[0].end), (0, 4));
+        assert_eq!(line.items.len(), 1);
+        assert_eq!(render_data.data.runs[0].clusters, (0, 4));
+    }
+
+    #[test]
+    fn test_fragment_font_features_change_glyphs() {
+        fn glyph_ids(render_data: &RenderData) -> Vec<u16> {
+            render_data
+                .lines()
+                .flat_map(|line| line.runs())
+                .flat_map(|run| run.clusters())
+                .flat_map(|cluster| cluster.glyphs().map(|glyph| glyph.id))
+                .collect()
+        }
+
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+
+        let mut plain = RenderData::new();
+        let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+        lb.add_text("0", FragmentStyle::default());
+        lb.build_into(&mut plain);
+        plain.break_lines().break_without_advance_or_alignment();
+
+        // Cascadia uses ss19 for the slashed zero.
+        let mut slashed = RenderData::new();
+        let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+        let features = lb.add_font_features([Setting::from(("ss19", 1))]);
+        lb.add_text(
+            "0",
+            FragmentStyle {
+                font_features: features,
+                ..FragmentStyle::default()
+            },
+        );
+        lb.build_into(&mut slashed);
+        slashed.break_lines().break_without_advance_or_alignment();
+
+        assert_ne!(glyph_ids(&plain), glyph_ids(&slashed));
+    }
+
+    #[test]
+    fn test_set_ligatures_toggles_contextual_glyphs() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let shape = |lcx: &mut LayoutContext, text: &str| {
+            let mut render_data = RenderData::new();
+            let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+            lb.set_hash(text.len() as u64);
+            lb.add_text(text, FragmentStyle::default());
+            lb.build_into(&mut render_data);
+            render_data
+                .break_lines()
+                .break_without_advance_or_alignment();
+            render_data
+                .lines()
+                .flat_map(|line| line.runs())
+                .flat_map(|run| run.clusters())
+                // Skip the trailing space `resolve` appends to the text.
+                .filter(|cluster| cluster.range().end <= text.len())
+                .map(|cluster| cluster.glyphs().map(|glyph| glyph.id).collect())
+                .collect::<Vec<Vec<u16>>>()
+        };
+
+        // Cascadia draws `!=` with contextual alternates.
+        let on = shape(&mut lcx, "!=");
+        lcx.set_ligatures(false);
+        let off = shape(&mut lcx, "!=");
+        assert_eq!(off.len(), 2);
+        assert_ne!(on, off);
+        // Without contextual alternates each character keeps its glyph.
+        let bang = shape(&mut lcx, "!");
+        assert_eq!(off[0], bang[0]);
+
+        lcx.set_ligatures(true);
+        assert_eq!(shape(&mut lcx, "!="), on);
+    }
+
+    #[test]
+    fn test_empty_font_library_lays_out_nothing() {
+        let font_library = FontLibrary {
+            inner: std::sync::Arc::new(
+                std::sync::RwLock::new(FontLibraryData::default()),
+            ),
+        };
+        let mut lcx = LayoutContext::new(&font_library);
+        let mut render_data = RenderData::new();
+        let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+        lb.add_text("abc", FragmentStyle::default());
+        lb.build_into(&mut render_data);
+        render_data
+            .break_lines()
+            .break_without_advance_or_alignment();
+
+        assert!(render_data.data.runs.is_empty());
+        assert!(render_data.data.glyphs.is_empty());
+        assert_eq!(render_data.lines().count(), 0);
+    }
+
+    #[test]
+    fn test_font_setting_cache_deduplicates_lists() {
+        let mut cache = FontSettingCache::<u16>::default();
+        let a = cache.add([("ss02", 1).into(), ("calt", 0).into()].into_iter());
+        let b = cache.add([("calt", 0).into(), ("ss02", 1).into()].into_iter());
+        assert_eq!(a, b);
+        assert_eq!(cache.get(a).len(), 2);
+        assert_eq!(cache.add(core::iter::empty()), EMPTY_FONT_SETTINGS);
+    }
+
+    #[test]
+    fn test_run_cache_flushes_over_capacity() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::with_cache_capacity(&font_library, 2);
+
+        let mut render_data = RenderData::new();
+        let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+        for hash in 1..=3 {
+            lb.set_hash(hash);
+            lb.add_text("abc", FragmentStyle::default());
+            lb.new_line();
+        }
+        lb.build_into(&mut render_data);
+        assert_eq!(lcx.cache.inner.len(), 3);
+
+        // The next build starts over capacity, so older lines are evicted.
+        let mut render_data = RenderData::new();
+        let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+        lb.set_hash(4);
+        lb.add_text("abc", FragmentStyle::default());
+        lb.build_into(&mut render_data);
+        assert_eq!(lcx.cache.inner.len(), 1);
+        assert!(lcx.cache.inner.contains_key(&4));
+    }
+
+    #[test]
+    fn test_ensure_cache_capacity_keeps_every_row() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::with_cache_capacity(&font_library, 16);
+        lcx.ensure_cache_capacity(8);
+        assert_eq!(lcx.cache.capacity, 16);
+        lcx.ensure_cache_capacity(300);
+        assert_eq!(lcx.cache.capacity, 600);
+
+        let mut render_data = RenderData::new();
+        let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+        for line in 1..=300 {
+            lb.set_hash(line);
+            lb.add_text(&format!("row {line}"), FragmentStyle::default());
+            lb.new_line();
+        }
+        lb.build_into(&mut render_data);
+        assert_eq!(lcx.cache.inner.len(), 300);
+
+        // The next layout starts by flushing a full cache, every row
+        // must survive it to be reused.
+        lcx.cache.clear_on_max_capacity();
+        let mut render_data = RenderData::new();
+        let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+        for line in 1..=300 {
+            lb.set_hash(line);
+            lb.new_line();
+        }
+        for line in 0..300 {
+            assert!(lb.process_from_cache(&mut render_data, line));
+        }
+    }
+
+    #[test]
+    fn test_build_into_range_lays_out_requested_lines() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let mut render_data = RenderData::new();
+        let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+        for line in 0..6 {
+            lb.set_hash(line + 1);
+            lb.add_text(&"a".repeat(line as usize + 1), FragmentStyle::default());
+            lb.new_line();
+        }
+        lb.build_into_range(&mut render_data, 2..4);
+
+        assert_eq!(render_data.line_from_cache.len(), 2);
+        render_data
+            .break_lines()
+            .break_without_advance_or_alignment();
+        let clusters: Vec<usize> = render_data
+            .lines()
+            .map(|line| line.runs().map(|run| run.clusters().count()).sum())
+            .collect();
+        assert_eq!(clusters, vec![3, 4]);
+        assert_eq!(lcx.cache.inner.len(), 2);
+
+        // Without the trailing space every glyph keeps its advance.
+        let advances: Vec<f32> = render_data.lines().map(|line| line.advance()).collect();
+        let cell = advances[0] / 3.;
+        assert!(cell > 0.);
+        assert!((advances[1] - cell * 4.).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_scale_change_reshapes_cached_lines() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let layout = |lcx: &mut LayoutContext, scale: f32| {
+            let mut render_data = RenderData::new();
+            let mut lb = lcx.builder(Direction::LeftToRight, None, scale);
+            lb.set_hash(1);
+            lb.add_text("abc", FragmentStyle::default());
+            lb.build_into(&mut render_data);
+            let advance = render_data.runs().next().unwrap().advance();
+            (advance, render_data.line_from_cache[0])
+        };
+
+        let (small, _) = layout(&mut lcx, 1.);
+        let (large, from_cache) = layout(&mut lcx, 2.);
+        assert!(!from_cache);
+        assert!((large - small * 2.).abs() < 1.);
+        let (_, from_cache) = layout(&mut lcx, 2.);
+        assert!(from_cache);
+
+        // Building at another scale, e.g. to measure, keeps cached lines.
+        lcx.measure("x", FragmentStyle::default());
+        let (_, from_cache) = layout(&mut lcx, 2.);
+        assert!(from_cache);
+    }
+
+    #[test]
+    fn test_cache_stats_count_hits_on_second_layout() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let layout = |lcx: &mut LayoutContext| {
+            let mut render_data = RenderData::new();
+            let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+            for hash in 1..=3 {
+                lb.set_hash(hash);
+                lb.add_text("abc", FragmentStyle::default());
+                lb.new_line();
+            }
+            lb.build_into(&mut render_data);
+            lcx.take_stats()
+        };
+
+        let first = layout(&mut lcx);
+        assert_eq!(first.run_hits, 0);
+        assert_eq!(first.run_misses, 3);
+        let second = layout(&mut lcx);
+        assert_eq!(second.run_hits, 3);
+        assert_eq!(second.run_misses, 0);
+        assert_eq!(lcx.take_stats(), CacheStats::default());
+    }
+
+    #[test]
+    fn test_line_from_cache_marks_cached_lines() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let mut render_data = RenderData::new();
+        let mut layout = |lcx: &mut LayoutContext| {
+            render_data.clear();
+            let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+            for hash in 1..=3 {
+                lb.set_hash(hash);
+                lb.add_text("abc", FragmentStyle::default());
+                lb.new_line();
+            }
+            lb.build_into(&mut render_data);
+            // The last line has no hash and is never cached.
+            render_data.line_from_cache[..3].to_vec()
+        };
+
+        assert_eq!(layout(&mut lcx), vec![false; 3]);
+        assert_eq!(layout(&mut lcx), vec![true; 3]);
+    }
+
+    #[test]
+    fn test_invalidate_line_reshapes_only_that_line() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let layout = |lcx: &mut LayoutContext| {
+            let mut render_data = RenderData::new();
+            let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+            for hash in 1..=3 {
+                lb.set_hash(hash);
+                lb.add_text("abc", FragmentStyle::default());
+                lb.new_line();
+            }
+            lb.build_into(&mut render_data);
+            lcx.take_stats()
+        };
+
+        layout(&mut lcx);
+        lcx.invalidate_line(2);
+        let stats = layout(&mut lcx);
+        assert_eq!(stats.run_hits, 2);
+        assert_eq!(stats.run_misses, 1);
+
+        lcx.invalidate_lines(&[1, 3]);
+        let stats = layout(&mut lcx);
+        assert_eq!(stats.run_hits, 1);
+        assert_eq!(stats.run_misses, 2);
+    }
+
+    #[test]
+    fn test_replace_line_keeps_other_cached_lines() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let mut content = crate::layout::Content::builder();
+        for line in 0..8 {
+            if line > 0 {
+                content.break_line();
+            }
+            content.add_text(&format!("line {line}"), FragmentStyle::default());
+            content.set_current_line_hash(line + 1);
+        }
+
+        let layout = |lcx: &mut LayoutContext,
+                      content: &crate::layout::ContentBuilder| {
+            let mut render_data = RenderData::new();
+            let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+            content.build_ref().layout(&mut lb);
+            lb.build_into(&mut render_data);
+        };
+        layout(&mut lcx, &content);
+        let cached =
+            |lcx: &LayoutContext, hash: u64| format!("{:?}", lcx.cache.inner[&hash]);
+        let before: Vec<String> = (1..=8).map(|hash| cached(&lcx, hash)).collect();
+
+        assert!(content.replace_line(5, &[("changed", FragmentStyle::default())]));
+        let new_hash = content.build_ref().fragments[5].hash();
+        assert_ne!(new_hash, 6);
+        assert!(content.build_ref().text.contains("line 4\nchanged\nline 6"));
+        layout(&mut lcx, &content);
+
+        assert!(lcx.cache.inner.contains_key(&new_hash));
+        for hash in (1..=8).filter(|hash| *hash != 6) {
+            assert_eq!(cached(&lcx, hash), before[hash as usize - 1]);
+        }
+    }
+
+    #[test]
+    fn test_prewarm_fills_run_cache() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let mut content = crate::layout::Content::builder();
+        for line in 0..4 {
+            if line > 0 {
+                content.break_line();
+            }
+            content.add_text(&format!("line {line}"), FragmentStyle::default());
+            content.set_current_line_hash(line + 1);
+        }
+        let content = content.build();
+
+        lcx.prewarm(&content, 1.);
+        for hash in 1..=4 {
+            assert!(lcx.cache.inner.contains_key(&hash));
+        }
+
+        let mut render_data = RenderData::new();
+        let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+        content.layout(&mut lb);
+        for line in 0..4 {
+            assert!(lb.process_from_cache(&mut render_data, line));
+        }
+    }
+
+    #[test]
+    fn test_measure_matches_cluster_advances() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let mut render_data = RenderData::new();
+        let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+        lb.add_text("hello", FragmentStyle::default());
+        lb.build_into(&mut render_data);
+        render_data
+            .break_lines()
+            .break_without_advance_or_alignment();
+        let advance: f32 = render_data
+            .lines()
+            .flat_map(|line| line.runs())
+            .flat_map(|run| run.clusters())
+            .map(|cluster| cluster.advance())
+            .sum();
+
+        let measured = lcx.measure("hello", FragmentStyle::default());
+        assert!(advance > 0.);
+        assert!((measured - advance).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_decoration_thickness_scales_underline() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let underline_size = |lcx: &mut LayoutContext| {
+            let mut render_data = RenderData::new();
+            let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+            lb.add_text(
+                "abc",
+                FragmentStyle {
+                    underline: true,
+                    ..FragmentStyle::default()
+                },
+            );
+            lb.build_into(&mut render_data);
+            render_data
+                .break_lines()
+                .break_without_advance_or_alignment();
+            let run = render_data.runs().next().unwrap();
+            (run.underline_size(), run.strikeout_size())
+        };
+
+        let (underline, strikeout) = underline_size(&mut lcx);
+        lcx.set_decoration_thickness(2.);
+        assert_eq!(underline_size(&mut lcx), (underline * 2., strikeout * 2.));
+        lcx.set_decoration_thickness(0.);
+        assert_eq!(underline_size(&mut lcx), (1., 1.));
+    }
+
+    #[test]
+    fn test_underline_offset_adjust_moves_underline() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let underline_offset = |lcx: &mut LayoutContext| {
+            let mut render_data = RenderData::new();
+            let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+            lb.add_text(
+                "gyp",
+                FragmentStyle {
+                    underline: true,
+                    underline_offset: Some(-2.),
+                    ..FragmentStyle::default()
+                },
+            );
+            lb.build_into(&mut render_data);
+            render_data
+                .break_lines()
+                .break_without_advance_or_alignment();
+            render_data.runs().next().unwrap().underline_offset()
+        };
+
+        assert_eq!(underline_offset(&mut lcx), -2.);
+        lcx.set_underline_offset_adjust(-1.5);
+        assert_eq!(underline_offset(&mut lcx), -3.5);
+    }
+
+    #[test]
+    fn test_cell_metrics_scale_with_font_size() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+
+        let small = lcx.cell_metrics(16., 1.);
+        let large = lcx.cell_metrics(32., 1.);
+        assert!(small.advance > 0.);
+        assert!((large.advance - small.advance * 2.).abs() <= 1.);
+        // Ascent and descent are rounded to whole pixels.
+        assert!((large.ascent - small.ascent * 2.).abs() <= 2.);
+        assert!((large.descent - small.descent * 2.).abs() <= 2.);
+
+        assert_eq!(lcx.cell_metrics(16., 2.).advance, large.advance);
+        assert_eq!(lcx.cell_metrics(16., 1.), small);
+        assert_eq!(lcx.cell_metrics.len(), 3);
+    }
+
+    #[test]
+    fn test_tab_advances_to_next_tab_stop() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        lcx.set_tab_width(8);
+        let mut render_data = RenderData::new();
+        let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+        lb.add_text("abc\tx", FragmentStyle::default());
+        lb.build_into(&mut render_data);
+        render_data
+            .break_lines()
+            .break_without_advance_or_alignment();
+
+        let clusters: Vec<_> = render_data
+            .lines()
+            .flat_map(|line| line.runs())
+            .flat_map(|run| run.clusters())
+            .collect();
+        let cell = clusters[0].advance();
+        // The tab stays a single cluster that maps to its own offset.
+        assert_eq!(clusters[3].offset(), 3);
+        assert!((clusters[3].advance() - cell * 5.).abs() < 0.1);
+        let x = render_data.line_data.clusters[4].1;
+        assert!((x - cell * 8.).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_tab_render_arrow_draws_indicator() {
+        let font_library = FontLibrary::default();
+        let arrow = {
+            let library = font_library.inner.read().unwrap();
+            library[FONT_ID_REGULAR].as_ref().charmap().map('\u{2192}')
+        };
+        let mut lcx = LayoutContext::new(&font_library);
+        let tab_glyph = |lcx: &mut LayoutContext| {
+            let mut render_data = RenderData::new();
+            let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+            lb.add_text("a\tb", FragmentStyle::default());
+            lb.build_into(&mut render_data);
+            let run = render_data.runs().next().unwrap();
+            let tab = run.clusters().nth(1).unwrap();
+            assert_eq!(tab.offset(), 1);
+            tab.glyphs().next().map(|glyph| glyph.id)
+        };
+
+        assert_ne!(arrow, 0);
+        assert_ne!(tab_glyph(&mut lcx), Some(arrow));
+        lcx.set_tab_render(TabRender::Arrow);
+        assert_eq!(tab_glyph(&mut lcx), Some(arrow));
+    }
+
+    #[test]
+    fn test_control_char_mode_caret_and_hide() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let control_glyphs = |lcx: &mut LayoutContext| {
+            let mut render_data = RenderData::new();
+            let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+            lb.add_text("\x01b", FragmentStyle::default());
+            lb.build_into(&mut render_data);
+            let clusters: Vec<_> =
+                render_data.runs().flat_map(|run| run.clusters()).collect();
+            // The character after the control code keeps its offset.
+            assert!(clusters.iter().any(|cluster| cluster.offset() == 1));
+            clusters
+                .iter()
+                .filter(|cluster| cluster.offset() == 0)
+                .flat_map(|cluster| cluster.glyphs())
+                .count()
+        };
+
+        lcx.set_control_char_mode(ControlCharMode::Caret);
+        assert_eq!(control_glyphs(&mut lcx), 2);
+        lcx.set_control_char_mode(ControlCharMode::Hide);
+        assert_eq!(control_glyphs(&mut lcx), 0);
+        lcx.set_control_char_mode(ControlCharMode::Replacement);
+        assert_eq!(control_glyphs(&mut lcx), 1);
+
+        // Line breaks are never replaced.
+        let mut render_data = RenderData::new();
+        let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+        lb.add_text("a\nb", FragmentStyle::default());
+        lb.build_into(&mut render_data);
+        assert!(render_data
+            .runs()
+            .flat_map(|run| run.clusters())
+            .any(|cluster| cluster.is_newline()));
+    }
+
+    #[test]
+    fn test_presentation_selector_only_follows_emoji() {
+        for ch in ['1', '#', '*', 'a', ' ', '.'] {
+            assert!(!needs_presentation_selector(ch, None));
+        }
+        assert!(needs_presentation_selector('\u{260E}', None));
+        assert!(needs_presentation_selector('\u{260E}', Some('a')));
+        // Explicit selectors in the text win over the fragment style.
+        assert!(!needs_presentation_selector('\u{260E}', Some('\u{FE0E}')));
+        assert!(!needs_presentation_selector('\u{260E}', Some('\u{FE0F}')));
+    }
+
+    #[test]
+    fn test_emoji_presentation_keeps_cluster_range() {
+        let font_library = FontLibrary::default();
+        let has_emoji_font =
+            font_library
+                .inner
+                .read()
+                .unwrap()
+                .inner
+                .iter()
+                .any(|source| match source {
+                    crate::font::FontSource::Data(data) => data.is_emoji,
+                    _ => false,
+                });
+        let mut lcx = LayoutContext::new(&font_library);
+        let mut shape = |emoji_presentation| {
+            let mut render_data = RenderData::new();
+            let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+            lb.add_text(
+                "☎",
+                FragmentStyle {
+                    emoji_presentation,
+                    ..FragmentStyle::default()
+                },
+            );
+            lb.build_into(&mut render_data);
+            render_data
+                .break_lines()
+                .break_without_advance_or_alignment();
+            let cluster = render_data
+                .lines()
+                .flat_map(|line| line.runs())
+                .flat_map(|run| run.clusters())
+                .next()
+                .unwrap();
+            let glyphs: Vec<u16> = cluster.glyphs().map(|glyph| glyph.id).collect();
+            (cluster.range(), glyphs)
+        };
+
+        let (text_range, text_glyphs) = shape(Some(false));
+        let (emoji_range, emoji_glyphs) = shape(Some(true));
+        // Selectors never extend the cluster past the source character.
+        assert_eq!(text_range, 0.."☎".len());
+        assert_eq!(emoji_range, 0.."☎".len());
+        if has_emoji_font {
+            assert_ne!(text_glyphs, emoji_glyphs);
+        }
+    }
+
+    #[test]
+    fn test_zwj_sequence_is_one_cluster() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        let mut render_data = RenderData::new();
+        let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+        lb.add_text(family, FragmentStyle::default());
+        lb.build_into(&mut render_data);
+        render_data
+            .break_lines()
+            .break_without_advance_or_alignment();
+
+        let clusters: Vec<_> = render_data
+            .lines()
+            .flat_map(|line| line.runs())
+            .flat_map(|run| run.clusters())
+            // Skip the trailing space `resolve` appends to the text.
+            .filter(|cluster| cluster.range().end <= family.len())
+            .collect();
+        assert_eq!(clusters.len(), 1);
+        assert_eq!(clusters[0].range(), 0..family.len());
+        // Fonts without the combined glyph still draw the components.
+        assert!(clusters[0].glyphs().count() >= 1);
+    }
+
+    #[test]
+    fn test_bold_on_regular_only_font_is_emboldened() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let mut shape = |text: &str| {
+            let mut render_data = RenderData::new();
+            let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+            let mut style = FragmentStyle::default();
+            style.font_attrs.1 = crate::font::Weight::BOLD;
+            lb.add_text(text, style);
+            lb.build_into(&mut render_data);
+            render_data
+                .break_lines()
+                .break_without_advance_or_alignment();
+            let run = render_data.lines().flat_map(|line| line.runs()).next();
+            run.map(|run| (*run.font(), run.synthesis())).unwrap()
+        };
+
+        // Cascadia ships a real bold face.
+        let (font, synthesis) = shape("a");
+        assert_eq!(font, FONT_ID_REGULAR);
+        assert!(!synthesis.embolden);
+
+        // The bundled nerd font symbols only come in a regular weight.
+        let (font, synthesis) = shape("\u{f121}");
+        assert_ne!(font, FONT_ID_REGULAR);
+        assert!(synthesis.embolden);
+        assert_eq!(synthesis.skew, 0.);
+    }
+
+    #[test]
+    fn test_content_variations_reach_the_shaper() {
+        let wght = swash::tag_from_bytes(b"wght");
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let mut content = crate::layout::Content::builder();
+        content.add_text_with_vars("a", FragmentStyle::default(), &[(wght, 400.)]);
+        content.break_line();
+        content.add_text_with_vars("a", FragmentStyle::default(), &[(wght, 650.)]);
+
+        let mut render_data = RenderData::new();
+        let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+        content.build_ref().layout(&mut lb);
+        lb.build_into(&mut render_data);
+        render_data
+            .break_lines()
+            .break_without_advance_or_alignment();
+
+        // The bundled fonts are static, so only the settings handed to the
+        // shaper can be checked here rather than the resulting outlines.
+        let vars: Vec<Vec<Setting<f32>>> = render_data
+            .lines()
+            .flat_map(|line| line.runs())
+            .filter(|run| run.span().font_vars != EMPTY_FONT_SETTINGS)
+            .map(|run| lcx.state.vars.get(run.span().font_vars).to_vec())
+            .collect();
+        assert_eq!(
+            vars,
+            vec![
+                vec![Setting {
+                    tag: wght,
+                    value: 400.
+                }],
+                vec![Setting {
+                    tag: wght,
+                    value: 650.
+                }],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_monospace_snap_aligns_box_drawing_to_cells() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        // A cell wider than the glyphs, as with a custom cell width.
+        let cell = lcx.cell_metrics(16., 1.).advance + 1.3;
+        lcx.set_monospace_snap(true, cell);
+        let mut render_data = RenderData::new();
+        let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+        lb.add_text(
+            "─┼─╋━",
+            FragmentStyle {
+                font_size: 16.,
+                ..FragmentStyle::default()
+            },
+        );
+        lb.build_into(&mut render_data);
+        render_data
+            .break_lines()
+            .break_without_advance_or_alignment();
+
+        let clusters: Vec<_> = render_data
+            .lines()
+            .flat_map(|line| line.runs())
+            .flat_map(|run| run.clusters())
+            .collect();
+        for (i, cluster) in clusters.iter().take(5).enumerate() {
+            assert!((cluster.advance() - cell).abs() < 0.001);
+            let glyphs: f32 = cluster.glyphs().map(|glyph| glyph.advance).sum();
+            assert!((glyphs - cell).abs() < 0.001);
+            let x = render_data.line_data.clusters[i].1;
+            assert!((x - cell * i as f32).abs() < 0.001);
+        }
+    }
+
+    #[test]
+    fn test_fallback_trace_marks_fallback_runs() {
+        let font_library = FontLibrary::default();
+        let has_emoji_font =
+            font_library
+                .inner
+                .read()
+                .unwrap()
+                .inner
+                .iter()
+                .any(|source| match source {
+                    crate::font::FontSource::Data(data) => data.is_emoji,
+                    _ => false,
+                });
+        let mut lcx = LayoutContext::new(&font_library);
+        let fallbacks = |lcx: &mut LayoutContext, text: &str| {
+            let mut render_data = RenderData::new();
+            let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+            lb.add_text(text, FragmentStyle::default());
+            lb.build_into(&mut render_data);
+            render_data
+                .break_lines()
+                .break_without_advance_or_alignment();
+            render_data
+                .lines()
+                .flat_map(|line| line.runs())
+                .map(|run| (*run.font(), run.is_fallback()))
+                .collect::<Vec<_>>()
+        };
+
+        // Without the trace nothing is recorded.
+        assert!(fallbacks(&mut lcx, "a\u{f121}").iter().all(|run| !run.1));
+
+        lcx.set_fallback_trace(true);
+        let runs = fallbacks(&mut lcx, "a\u{f121}");
+        assert_eq!(runs[0], (FONT_ID_REGULAR, false));
+        assert_ne!(runs[1].0, FONT_ID_REGULAR);
+        assert!(runs[1].1);
+        if has_emoji_font {
+            let runs = fallbacks(&mut lcx, "a😀");
+            assert!(runs[1].1);
+        }
+    }
+
+    #[test]
+    fn test_cached_line_keeps_mid_line_font_switch() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let layout = |lcx: &mut LayoutContext| {
+            let mut render_data = RenderData::new();
+            let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+            lb.set_hash(1);
+            lb.add_text("a\u{f121}b", FragmentStyle::default());
+            lb.build_into(&mut render_data);
+            let runs = render_data
+                .runs()
+                .map(|run| {
+                    let glyphs: Vec<u16> = run
+                        .clusters()
+                        .flat_map(|cluster| cluster.glyphs())
+                        .map(|glyph| glyph.id)
+                        .collect();
+                    (*run.font(), glyphs)
+                })
+                .collect::<Vec<_>>();
+            (runs, render_data.line_from_cache[0])
+        };
+
+        let (shaped, from_cache) = layout(&mut lcx);
+        assert!(!from_cache);
+        assert!(shaped.len() >= 3);
+        assert_ne!(shaped[1].0, shaped[0].0);
+        let (cached, from_cache) = layout(&mut lcx);
+        assert!(from_cache);
+        assert_eq!(cached, shaped);
+    }
+}
@@ -10,9 +10,49 @@
 // and other functionalities
 
 use super::span_style::*;
+use super::LayoutDirection;
 use swash::text::{cluster::CharInfo, Script};
 use swash::Setting;
 
+/// How tab characters are drawn, see `LayoutContext::set_tab_render`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TabRender {
+    /// Tabs only take space.
+    #[default]
+    Blank,
+    /// Tabs show an arrow at the start of their width.
+    Arrow,
+    /// Tabs show a middle dot at the start of their width.
+    Dots,
+}
+
+impl TabRender {
+    /// Character drawn for a tab, if any.
+    #[inline]
+    pub fn indicator(self) -> Option<char> {
+        match self {
+            TabRender::Blank => None,
+            TabRender::Arrow => Some('\u{2192}'),
+            TabRender::Dots => Some('\u{b7}'),
+        }
+    }
+}
+
+/// How control characters other than tabs and line breaks are laid
+/// out, see `LayoutContext::set_control_char_mode`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ControlCharMode {
+    /// Control characters are shaped as is, usually as missing glyphs.
+    #[default]
+    Keep,
+    /// Control characters are dropped and take no space.
+    Hide,
+    /// Control characters show in caret notation, e.g. `^A`.
+    Caret,
+    /// Control characters show as U+FFFD.
+    Replacement,
+}
+
 /// Data that describes a fragment.
 #[derive(Copy, Debug, Clone)]
 pub struct FragmentData {
@@ -86,8 +126,35 @@ pub struct BuilderState {
     pub features: FontSettingCache<u16>,
     /// Font variation setting cache.
     pub vars: FontSettingCache<f32>,
+    /// Font features applied to every fragment.
+    pub global_features: Vec<Setting<u16>>,
     /// User specified scale.
     pub scale: f32,
+    /// Amount of cells between tab stops, 0 disables tab expansion.
+    pub tab_width: u32,
+    /// Indicator drawn for tab characters.
+    pub tab_render: TabRender,
+    /// Layout of control characters.
+    pub control_char_mode: ControlCharMode,
+    /// Snap cluster advances to whole multiples of `cell_advance`.
+    pub monospace_snap: bool,
+    /// Width of a terminal cell used by `monospace_snap`.
+    pub cell_advance: f32,
+    /// Record on each run whether its font came from fallback.
+    pub fallback_trace: bool,
+    /// Leave the `liga` and `calt` features to the font, otherwise they
+    /// are turned off.
+    pub ligatures: bool,
+    /// Lines shorter than this are padded up to it.
+    pub min_line_height: Option<f32>,
+    /// Multiplier of underline and strikeout thicknesses.
+    pub decoration_thickness: Option<f32>,
+    /// Amount added to every underline offset.
+    pub underline_offset_adjust: f32,
+    /// Color reported by clusters without a glyph in any font.
+    pub notdef_color: Option<[f32; 4]>,
+    /// Direction lines advance and stack in.
+    pub layout_direction: LayoutDirection,
 }
 
 impl BuilderState {
@@ -97,6 +164,8 @@ impl BuilderState {
         lines[0].styles.push(FragmentStyle::default());
         Self {
             lines,
+            tab_width: 8,
+            ligatures: true,
             ..BuilderState::default()
         }
     }
@@ -137,7 +206,7 @@ pub type FontSettingKey = u32;
 pub const EMPTY_FONT_SETTINGS: FontSettingKey = !0;
 
 /// Cache of tag/value pairs for font settings.
-#[derive(Default)]
+#[derive(Default, Clone, PartialEq)]
 pub struct FontSettingCache<T: Copy + PartialOrd + PartialEq> {
     settings: Vec<Setting<T>>,
     lists: Vec<FontSettingList>,
@@ -156,6 +225,33 @@ impl<T: Copy + PartialOrd + PartialEq> FontSettingCache<T> {
         }
     }
 
+    /// Interns a list of settings and returns its key. Settings are sorted
+    /// by tag and, for duplicated tags, the last value wins.
+    pub fn add(&mut self, values: impl Iterator<Item = Setting<T>>) -> FontSettingKey {
+        self.tmp.clear();
+        for setting in values {
+            if let Some(existing) = self.tmp.iter_mut().find(|s| s.tag == setting.tag) {
+                existing.value = setting.value;
+            } else {
+                self.tmp.push(setting);
+            }
+        }
+        if self.tmp.is_empty() {
+            return EMPTY_FONT_SETTINGS;
+        }
+        self.tmp.sort_by_key(|s| s.tag);
+        for (key, list) in self.lists.iter().enumerate() {
+            if list.get(&self.settings) == self.tmp.as_slice() {
+                return key as FontSettingKey;
+            }
+        }
+        let start = self.settings.len() as u32;
+        self.settings.extend_from_slice(&self.tmp);
+        let end = self.settings.len() as u32;
+        self.lists.push(FontSettingList { start, end });
+        (self.lists.len() - 1) as FontSettingKey
+    }
+
     pub fn clear(&mut self) {
         self.settings.clear();
         self.lists.clear();
@@ -164,7 +260,7 @@ impl<T: Copy + PartialOrd + PartialEq> FontSettingCache<T> {
 }
 
 /// Range within a font setting cache.
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 struct FontSettingList {
     pub start: u32,
     pub end: u32,
@@ -6,10 +6,13 @@
 // content.rs was originally retired from dfrg/swash_demo licensed under MIT
 // https://github.com/dfrg/swash_demo/blob/master/LICENSE
 
+use crate::layout::builder_data::{FontSettingCache, EMPTY_FONT_SETTINGS};
 use crate::layout::*;
 use core::ops::Range;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use swash::{Setting, Tag};
 
-#[derive(PartialEq, Debug, Clone)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub struct Fragment {
     start: u32,
     end: u32,
@@ -22,11 +25,46 @@ pub struct LineFragments {
     hash: u64,
 }
 
+impl LineFragments {
+    /// Returns the hash used to look up the line in the run cache.
+    #[inline]
+    pub fn hash(&self) -> u64 {
+        self.hash
+    }
+
+    /// Overrides the hash of the line, 0 means the line is never cached.
+    #[inline]
+    pub fn set_hash(&mut self, hash: u64) {
+        self.hash = hash;
+    }
+
+    /// Applies `style` to every fragment of the line.
+    #[inline]
+    pub fn set_style(&mut self, style: FragmentStyle) {
+        for fragment in &mut self.data {
+            fragment.style = style;
+        }
+    }
+}
+
+/// Lines that differ between two contents, see [`Content::diff`].
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum LineDiff {
+    /// Lines of the new content that replaced lines in place.
+    Changed(Range<usize>),
+    /// Lines only present in the new content.
+    Added(Range<usize>),
+    /// Lines only present in the old content, in old line indices.
+    Removed(Range<usize>),
+}
+
 #[derive(Clone)]
 pub struct Content {
     pub fragments: Vec<LineFragments>,
     pub text: String,
     pub current_line: usize,
+    /// Font variations referenced by `FragmentStyle::font_vars`.
+    vars: FontSettingCache<f32>,
 }
 
 impl Default for Content {
@@ -40,6 +78,7 @@ impl Default for Content {
             }],
             text: String::default(),
             current_line: 0,
+            vars: FontSettingCache::default(),
         }
     }
 }
@@ -49,6 +88,7 @@ impl PartialEq for Content {
         self.text == other.text
             && self.fragments == other.fragments
             && self.current_line == other.current_line
+            && self.vars == other.vars
     }
 }
 
@@ -61,18 +101,122 @@ impl Content {
     #[inline]
     pub fn layout(&self, lcx: &mut ParagraphBuilder) {
         for line in 0..self.current_line + 1 {
-            lcx.set_hash(self.fragments[line].hash);
+            self.layout_line(lcx, line);
+            lcx.new_line();
+        }
+    }
+
+    /// Adds the fragments of a single line to the current builder line.
+    pub(super) fn layout_line(&self, lcx: &mut ParagraphBuilder, line: usize) {
+        lcx.set_hash(self.fragments[line].hash);
 
-            for e in &self.fragments[line].data {
-                if e.start < e.end {
-                    if let Some(s) = self.text.get(e.start as usize..e.end as usize) {
-                        lcx.add_text(s, e.style);
+        for e in &self.fragments[line].data {
+            if e.start < e.end {
+                if let Some(s) = self.text.get(e.start as usize..e.end as usize) {
+                    let mut style = e.style;
+                    if style.font_vars != EMPTY_FONT_SETTINGS {
+                        // Keys are only valid for the cache that issued them.
+                        style.font_vars = lcx.add_font_variations(
+                            self.vars.get(style.font_vars).iter().copied(),
+                        );
                     }
+                    lcx.add_text(s, style);
                 }
             }
+        }
+    }
 
-            lcx.new_line();
+    /// Returns the byte length of the text of every line.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.text.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    /// Returns the amount of lines, including the current one.
+    #[inline]
+    pub fn line_count(&self) -> usize {
+        self.current_line + 1
+    }
+
+    /// Returns the text of the line at `index` without the newline added
+    /// by `break_line`.
+    pub fn line_text(&self, index: usize) -> Option<&str> {
+        if index > self.current_line {
+            return None;
+        }
+        let start = self.line_start(index) as usize;
+        let end = match self.fragments[index].data.last() {
+            Some(newline) if index < self.current_line => newline.start as usize,
+            Some(fragment) => fragment.end as usize,
+            None => start,
+        };
+        self.text.get(start..end)
+    }
+
+    /// Returns the fragments of a line for in place updates. Callers
+    /// that change the line must also update its hash.
+    #[inline]
+    pub fn line_mut(&mut self, index: usize) -> Option<&mut LineFragments> {
+        self.fragments.get_mut(index)
+    }
+
+    /// Compares the line hashes with `other` and reports which lines
+    /// changed, were added or were removed. Lines without a hash are
+    /// always reported as changed since they are never cached.
+    pub fn diff(&self, other: &Content) -> Vec<LineDiff> {
+        let old = &self.fragments;
+        let new = &other.fragments;
+        let same = |a: &LineFragments, b: &LineFragments| a.hash != 0 && a.hash == b.hash;
+
+        let prefix = old
+            .iter()
+            .zip(new.iter())
+            .take_while(|(a, b)| same(a, b))
+            .count();
+        let max_suffix = old.len().min(new.len()) - prefix;
+        let suffix = old
+            .iter()
+            .rev()
+            .zip(new.iter().rev())
+            .take(max_suffix)
+            .take_while(|(a, b)| same(a, b))
+            .count();
+
+        let old_end = old.len() - suffix;
+        let new_end = new.len() - suffix;
+        let changed = (old_end - prefix).min(new_end - prefix);
+        let mut diff = Vec::new();
+        if changed > 0 {
+            diff.push(LineDiff::Changed(prefix..prefix + changed));
+        }
+        if new_end > prefix + changed {
+            diff.push(LineDiff::Added(prefix + changed..new_end));
+        }
+        if old_end > prefix + changed {
+            diff.push(LineDiff::Removed(prefix + changed..old_end));
         }
+        diff
+    }
+
+    /// Byte offset where the text of the line starts.
+    fn line_start(&self, index: usize) -> u32 {
+        self.fragments[..=index]
+            .iter()
+            .rev()
+            .enumerate()
+            .find_map(|(distance, line)| {
+                if distance == 0 {
+                    line.data.first().map(|fragment| fragment.start)
+                } else {
+                    line.data.last().map(|fragment| fragment.end)
+                }
+            })
+            .unwrap_or(0)
     }
 
     pub fn get_selection_into(&self, range: Range<usize>, buf: &mut String) {
@@ -155,6 +299,22 @@ impl ContentBuilder {
             .push(Fragment { start, end, style });
     }
 
+    /// Adds a text fragment shaped with the given variation axis values,
+    /// e.g. `(tag_from_bytes(b"wght"), 650.)`. The values replace any
+    /// `font_vars` already set on `style`.
+    pub fn add_text_with_vars(
+        &mut self,
+        text: &str,
+        mut style: FragmentStyle,
+        vars: &[(Tag, f32)],
+    ) {
+        style.font_vars = self
+            .content
+            .vars
+            .add(vars.iter().map(|&(tag, value)| Setting { tag, value }));
+        self.add_text(text, style);
+    }
+
     #[inline]
     pub fn add_char(&mut self, text: char, style: FragmentStyle) {
         let start = self.content.text.len() as u32;
@@ -170,6 +330,81 @@ impl ContentBuilder {
         self.content.fragments[self.content.current_line].hash = hash;
     }
 
+    /// Replaces the fragments of the line at `index` and recomputes its
+    /// hash, so only this line is shaped again by the next layout.
+    /// Returns false if the line does not exist.
+    pub fn replace_line(
+        &mut self,
+        index: usize,
+        fragments: &[(&str, FragmentStyle)],
+    ) -> bool {
+        let content = &mut self.content;
+        if index > content.current_line {
+            return false;
+        }
+
+        // Lines ended by `break_line` keep their trailing newline.
+        let line = &content.fragments[index];
+        let newline = if index < content.current_line {
+            line.data.last().copied()
+        } else {
+            None
+        };
+        let start = content.line_start(index);
+        let end = match newline {
+            Some(fragment) => fragment.start,
+            None => line
+                .data
+                .last()
+                .map(|fragment| fragment.end)
+                .unwrap_or(start),
+        };
+
+        let mut text = String::new();
+        let mut data = Vec::with_capacity(fragments.len() + 1);
+        for (fragment_text, style) in fragments {
+            let fragment_start = start + text.len() as u32;
+            text.push_str(fragment_text);
+            data.push(Fragment {
+                start: fragment_start,
+                end: start + text.len() as u32,
+                style: *style,
+            });
+        }
+
+        content
+            .text
+            .replace_range(start as usize..end as usize, &text);
+        let delta = text.len() as i64 - (end - start) as i64;
+        let shift = |offset: u32| (offset as i64 + delta) as u32;
+        if let Some(mut fragment) = newline {
+            fragment.start = shift(fragment.start);
+            fragment.end = shift(fragment.end);
+            data.push(fragment);
+        }
+        for line in &mut content.fragments[index + 1..] {
+            for fragment in &mut line.data {
+                fragment.start = shift(fragment.start);
+                fragment.end = shift(fragment.end);
+            }
+        }
+
+        content.fragments[index] = LineFragments {
+            data,
+            hash: line_hash(fragments),
+        };
+        true
+    }
+
+    /// Adds `text` as a whole line and breaks it, hashing the line the
+    /// same way as `replace_line` so it's cached.
+    #[inline]
+    pub fn add_line(&mut self, text: &str, style: FragmentStyle) {
+        self.add_text(text, style);
+        self.set_current_line_hash(line_hash(&[(text, style)]));
+        self.break_line();
+    }
+
     #[inline]
     pub fn break_line(&mut self) {
         // Hacky: under the hood it will ignore this "\n" for break_line
@@ -195,3 +430,136 @@ impl ContentBuilder {
         self.content
     }
 }
+
+/// Hash of the fragments of a line, never 0 since 0 means the line
+/// is not cached.
+pub(super) fn line_hash(fragments: &[(&str, FragmentStyle)]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for (text, style) in fragments {
+        text.hash(&mut hasher);
+        style.hash(&mut hasher);
+    }
+    hasher.finish().max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_current_line_hash_targets_current_line() {
+        let mut builder = Content::builder();
+        builder.add_text("a", FragmentStyle::default());
+        builder.set_current_line_hash(10);
+        builder.break_line();
+        builder.add_text("b", FragmentStyle::default());
+        builder.set_current_line_hash(20);
+
+        let content = builder.build();
+        assert_eq!(content.fragments[0].hash, 10);
+        assert_eq!(content.fragments[1].hash, 20);
+    }
+
+    #[test]
+    fn test_break_line_starts_fresh_fragments() {
+        let mut builder = Content::builder();
+        builder.add_text("abc", FragmentStyle::default());
+        builder.break_line();
+
+        let content = builder.build_ref();
+        assert_eq!(content.current_line, 1);
+        assert_eq!(content.fragments.len(), 2);
+        assert!(content.fragments[1].data.is_empty());
+        assert_eq!(content.fragments[1].hash, 0);
+        // The newline stays attached to the previous line.
+        assert_eq!(content.fragments[0].data.len(), 2);
+        assert_eq!(content.text, "abc\n");
+    }
+
+    #[test]
+    fn test_add_line_breaks_and_hashes_lines() {
+        let mut builder = Content::builder();
+        builder.add_line("abc", FragmentStyle::default());
+        builder.add_line("de", FragmentStyle::default());
+
+        let content = builder.build_ref();
+        assert_eq!(content.text, "abc\nde\n");
+        assert_eq!(content.current_line, 2);
+        assert_eq!(content.fragments.len(), 3);
+        assert_ne!(content.fragments[0].hash, 0);
+        assert_ne!(content.fragments[0].hash, content.fragments[1].hash);
+        assert!(content.fragments[2].data.is_empty());
+
+        // Same hash as replacing the line with the same fragment.
+        let hash = content.fragments[0].hash;
+        builder.replace_line(0, &[("abc", FragmentStyle::default())]);
+        assert_eq!(builder.build_ref().fragments[0].hash, hash);
+    }
+
+    #[test]
+    fn test_line_text_joins_fragments() {
+        let mut builder = Content::builder();
+        builder.add_text("ab", FragmentStyle::default());
+        builder.add_text(
+            "cd",
+            FragmentStyle {
+                dim: true,
+                ..FragmentStyle::default()
+            },
+        );
+        builder.break_line();
+        builder.break_line();
+        builder.add_text("e", FragmentStyle::default());
+
+        let content = builder.build();
+        assert_eq!(content.len(), 7);
+        assert_eq!(content.line_count(), 3);
+        assert_eq!(content.line_text(0), Some("abcd"));
+        assert_eq!(content.line_text(1), Some(""));
+        assert_eq!(content.line_text(2), Some("e"));
+        assert_eq!(content.line_text(3), None);
+    }
+
+    fn content_with_hashes(hashes: &[u64]) -> Content {
+        let mut builder = Content::builder();
+        for (line, hash) in hashes.iter().enumerate() {
+            if line > 0 {
+                builder.break_line();
+            }
+            builder.add_text("a", FragmentStyle::default());
+            builder.set_current_line_hash(*hash);
+        }
+        builder.build()
+    }
+
+    #[test]
+    fn test_diff_identical_contents() {
+        let content = content_with_hashes(&[1, 2, 3]);
+        assert!(content.diff(&content.clone()).is_empty());
+    }
+
+    #[test]
+    fn test_diff_inserted_lines() {
+        let old = content_with_hashes(&[1, 2, 3]);
+        let new = content_with_hashes(&[1, 2, 7, 8, 3]);
+        assert_eq!(old.diff(&new), vec![LineDiff::Added(2..4)]);
+    }
+
+    #[test]
+    fn test_diff_removed_lines() {
+        let old = content_with_hashes(&[1, 2, 3, 4]);
+        let new = content_with_hashes(&[1, 4]);
+        assert_eq!(old.diff(&new), vec![LineDiff::Removed(1..3)]);
+    }
+
+    #[test]
+    fn test_diff_edited_lines() {
+        let old = content_with_hashes(&[1, 2, 3, 4]);
+        let new = content_with_hashes(&[1, 5, 6, 4]);
+        assert_eq!(old.diff(&new), vec![LineDiff::Changed(1..3)]);
+
+        // Lines without hash are never considered equal.
+        let old = content_with_hashes(&[1, 0, 3]);
+        assert_eq!(old.diff(&old.clone()), vec![LineDiff::Changed(1..2)]);
+    }
+}
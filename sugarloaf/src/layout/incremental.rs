@@ -0,0 +1,270 @@
+// Copyright (c) 2023-present, Raphael Amorim.
+//
+// This source code is licensed under the MIT license found in the
+// LICENSE file in the root directory of this source tree.
+
+use super::content::line_hash;
+use super::layout_data::*;
+use super::{Direction, FragmentStyle, LayoutContext, RenderData};
+use std::collections::VecDeque;
+
+/// Lengths of the layout buffers where a line starts.
+#[derive(Copy, Clone, Default, Debug)]
+struct LineStart {
+    coords: usize,
+    glyphs: usize,
+    detailed_glyphs: usize,
+    clusters: usize,
+    detailed_clusters: usize,
+    runs: usize,
+}
+
+impl LineStart {
+    fn of(data: &LayoutData) -> Self {
+        Self {
+            coords: data.coords.len(),
+            glyphs: data.glyphs.len(),
+            detailed_glyphs: data.detailed_glyphs.len(),
+            clusters: data.clusters.len(),
+            detailed_clusters: data.detailed_clusters.len(),
+            runs: data.runs.len(),
+        }
+    }
+}
+
+/// Layout of content that grows one line at a time, like the rows of a
+/// terminal. Each pushed line is shaped on its own, going through the
+/// run cache of the layout context, and appended to the existing layout
+/// so previous lines are never shaped again.
+pub struct IncrementalLayout {
+    lcx: LayoutContext,
+    render_data: RenderData,
+    scratch: RenderData,
+    starts: VecDeque<LineStart>,
+    scale: f32,
+}
+
+impl IncrementalLayout {
+    pub fn new(lcx: LayoutContext, scale: f32) -> Self {
+        Self {
+            lcx,
+            render_data: RenderData::new(),
+            scratch: RenderData::new(),
+            starts: VecDeque::new(),
+            scale,
+        }
+    }
+
+    /// Returns the layout context, e.g. to change its settings. Settings
+    /// only apply to lines pushed afterwards.
+    #[inline]
+    pub fn layout_context_mut(&mut self) -> &mut LayoutContext {
+        &mut self.lcx
+    }
+
+    /// Returns the layout of every line pushed so far.
+    #[inline]
+    pub fn render_data(&self) -> &RenderData {
+        &self.render_data
+    }
+
+    /// Returns the number of lines.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.starts.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.starts.is_empty()
+    }
+
+    /// Shapes a line and appends it after the existing ones.
+    pub fn push_line(&mut self, fragments: &[(&str, FragmentStyle)]) {
+        self.scratch.clear();
+        let mut lb = self.lcx.builder(Direction::LeftToRight, None, self.scale);
+        lb.set_hash(line_hash(fragments));
+        for (text, style) in fragments {
+            lb.add_text(text, *style);
+        }
+        lb.build_into(&mut self.scratch);
+
+        let data = &mut self.render_data.data;
+        let start = LineStart::of(data);
+        let line = self.starts.len() as u32;
+        let scratch = &mut self.scratch.data;
+        rebase(scratch, start, u32::wrapping_add);
+        for run in &mut scratch.runs {
+            run.line = line;
+        }
+        data.coords.extend_from_slice(&scratch.coords);
+        data.glyphs.extend_from_slice(&scratch.glyphs);
+        data.detailed_glyphs
+            .extend_from_slice(&scratch.detailed_glyphs);
+        data.clusters.extend_from_slice(&scratch.clusters);
+        data.detailed_clusters
+            .extend_from_slice(&scratch.detailed_clusters);
+        data.runs.extend_from_slice(&scratch.runs);
+        data.min_line_height = scratch.min_line_height;
+        data.decoration_thickness = scratch.decoration_thickness;
+        data.underline_offset_adjust = scratch.underline_offset_adjust;
+        data.notdef_color = scratch.notdef_color;
+        data.layout_direction = scratch.layout_direction;
+        self.starts.push_back(start);
+
+        self.break_lines();
+    }
+
+    /// Removes the first line, e.g. when it's evicted from the scrollback.
+    /// Returns false if there are no lines.
+    pub fn remove_top_line(&mut self) -> bool {
+        if self.starts.pop_front().is_none() {
+            return false;
+        }
+
+        let data = &mut self.render_data.data;
+        let end = self
+            .starts
+            .front()
+            .copied()
+            .unwrap_or_else(|| LineStart::of(data));
+        data.coords.drain(..end.coords);
+        data.glyphs.drain(..end.glyphs);
+        data.detailed_glyphs.drain(..end.detailed_glyphs);
+        data.clusters.drain(..end.clusters);
+        data.detailed_clusters.drain(..end.detailed_clusters);
+        data.runs.drain(..end.runs);
+        rebase(data, end, u32::wrapping_sub);
+        for run in &mut data.runs {
+            run.line -= 1;
+        }
+        for start in &mut self.starts {
+            start.coords -= end.coords;
+            start.glyphs -= end.glyphs;
+            start.detailed_glyphs -= end.detailed_glyphs;
+            start.clusters -= end.clusters;
+            start.detailed_clusters -= end.detailed_clusters;
+            start.runs -= end.runs;
+        }
+
+        self.break_lines();
+        true
+    }
+
+    #[inline]
+    fn break_lines(&mut self) {
+        // Breaking only walks the laid out clusters, nothing is shaped.
+        self.render_data
+            .break_lines()
+            .break_without_advance_or_alignment();
+    }
+}
+
+/// Moves every index of `data` by the lengths in `base`.
+fn rebase(data: &mut LayoutData, base: LineStart, op: fn(u32, u32) -> u32) {
+    for glyph in &mut data.glyphs {
+        if !glyph.is_simple() {
+            let index = op(glyph.detail_index() as u32, base.detailed_glyphs as u32);
+            glyph.data = GLYPH_DETAILED | index as u64;
+        }
+    }
+    for cluster in &mut data.clusters {
+        // Empty clusters store span or advance data instead of an index.
+        if cluster.is_detailed() {
+            cluster.glyphs = op(cluster.glyphs, base.detailed_clusters as u32);
+        } else if !cluster.is_empty() {
+            cluster.glyphs = op(cluster.glyphs, base.glyphs as u32);
+        }
+    }
+    for detail in &mut data.detailed_clusters {
+        detail.glyphs.0 = op(detail.glyphs.0, base.glyphs as u32);
+        detail.glyphs.1 = op(detail.glyphs.1, base.glyphs as u32);
+    }
+    for run in &mut data.runs {
+        run.coords.0 = op(run.coords.0, base.coords as u32);
+        run.coords.1 = op(run.coords.1, base.coords as u32);
+        run.clusters.0 = op(run.clusters.0, base.clusters as u32);
+        run.clusters.1 = op(run.clusters.1, base.clusters as u32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::font::FontLibrary;
+
+    type LineSignature = Vec<(std::ops::Range<usize>, Vec<(u16, f32, f32, f32)>)>;
+
+    fn lines(layout: &IncrementalLayout) -> Vec<LineSignature> {
+        layout
+            .render_data()
+            .lines()
+            .map(|line| {
+                line.runs()
+                    .flat_map(|run| run.clusters())
+                    .map(|cluster| {
+                        let glyphs = cluster
+                            .glyphs()
+                            .map(|glyph| (glyph.id, glyph.x, glyph.y, glyph.advance))
+                            .collect();
+                        (cluster.range(), glyphs)
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_remove_top_line_keeps_other_lines() {
+        let font_library = FontLibrary::default();
+        let mut layout = IncrementalLayout::new(LayoutContext::new(&font_library), 1.);
+        let style = FragmentStyle::default();
+        let wide = FragmentStyle {
+            letter_spacing: 1.,
+            ..FragmentStyle::default()
+        };
+        layout.push_line(&[("abc", style)]);
+        layout.push_line(&[("-> ", style), ("ffi", wide)]);
+        layout.push_line(&[("xyz", style)]);
+        assert_eq!(layout.len(), 3);
+
+        let before = lines(&layout);
+        assert_eq!(before.len(), 3);
+
+        assert!(layout.remove_top_line());
+        assert_eq!(layout.len(), 2);
+        assert_eq!(lines(&layout), before[1..]);
+
+        layout.push_line(&[("abc", style)]);
+        assert_eq!(lines(&layout)[2], before[0]);
+
+        assert!(layout.remove_top_line());
+        assert!(layout.remove_top_line());
+        assert!(layout.remove_top_line());
+        assert!(layout.is_empty());
+        assert!(layout.render_data().data.glyphs.is_empty());
+        assert!(!layout.remove_top_line());
+    }
+
+    #[test]
+    fn test_push_line_keeps_notdef_color() {
+        let font_library = FontLibrary::default();
+        let mut layout = IncrementalLayout::new(LayoutContext::new(&font_library), 1.);
+        let red = [1., 0., 0., 1.];
+        layout.layout_context_mut().set_notdef_color(Some(red));
+        let style = FragmentStyle {
+            color: [0., 0., 1., 1.],
+            ..FragmentStyle::default()
+        };
+        layout.push_line(&[("a\u{10FFFD}b", style)]);
+
+        let colors: Vec<[f32; 4]> = layout
+            .render_data()
+            .lines()
+            .flat_map(|line| line.runs())
+            .flat_map(|run| run.clusters())
+            .map(|cluster| cluster.color())
+            .collect();
+        assert_eq!(&colors[..3], &[style.color, red, style.color]);
+    }
+}
@@ -6,7 +6,8 @@
 // layout_data.rs was originally retired from dfrg/swash_demo licensed under MIT
 // https://github.com/dfrg/swash_demo/blob/master/LICENSE
 
-use super::{Alignment, Glyph};
+use super::render_data::make_range;
+use super::{Alignment, Glyph, LayoutDirection};
 use crate::layout::FragmentStyle;
 use swash::text::cluster::ClusterInfo;
 
@@ -136,19 +137,21 @@ pub struct DetailedClusterData {
     pub advance: f32,
 }
 
-pub const GLYPH_DETAILED: u32 = 0x80000000;
+pub const GLYPH_DETAILED: u64 = 1 << 63;
 
+/// Glyph packed in a single word: the id in the low 16 bits and the
+/// bits of the `f32` advance above it, so advances are exact at any
+/// size, or with `GLYPH_DETAILED` set, an index into the detailed glyphs.
 #[derive(Copy, Debug, Clone)]
 pub struct GlyphData {
-    pub data: u32,
+    pub data: u64,
     pub size: usize,
 }
 
 impl GlyphData {
     pub fn simple(id: u16, advance: f32, size: usize) -> Self {
-        let advance = (advance * 64.).max(0.) as u32;
         Self {
-            data: (id as u32 | (advance & 0x7FFF) << 16),
+            data: id as u64 | (advance.to_bits() as u64) << 16,
             size,
         }
     }
@@ -158,7 +161,10 @@ impl GlyphData {
     }
 
     pub fn simple_data(self) -> (u16, f32) {
-        ((self.data & 0xFFFF) as u16, (self.data >> 16) as f32 / 64.)
+        (
+            (self.data & 0xFFFF) as u16,
+            f32::from_bits((self.data >> 16) as u32),
+        )
     }
 
     pub fn detail_index(self) -> usize {
@@ -170,13 +176,31 @@ impl GlyphData {
         *self = Self::simple(id, (advance + spacing).max(0.), self.size);
     }
 
+    pub fn set_id(&mut self, id: u16) {
+        let (_id, advance) = self.simple_data();
+        *self = Self::simple(id, advance, self.size);
+    }
+
     pub fn clear_advance(&mut self) {
         let (id, _advance) = self.simple_data();
         *self = Self::simple(id, 0., self.size);
     }
 }
 
-#[derive(Copy, Debug, Clone)]
+/// Synthetic styling a renderer has to apply to a run when the font
+/// that ends up drawing it lacks the requested weight or style.
+///
+/// Advances are left untouched: the terminal grid is laid out from the
+/// shaped advances, so emboldening only thickens the outlines in place.
+#[derive(Copy, Debug, Clone, Default, PartialEq)]
+pub struct RunSynthesis {
+    /// Outlines should be emboldened.
+    pub embolden: bool,
+    /// Skew angle in degrees for a synthetic oblique, `0.` when none.
+    pub skew: f32,
+}
+
+#[derive(Copy, Debug, Clone, Default)]
 pub struct RunData {
     pub span: FragmentStyle,
     pub line: u32,
@@ -186,13 +210,19 @@ pub struct RunData {
     pub level: u8,
     pub whitespace: bool,
     pub trailing_whitespace: bool,
+    /// Advance of the trailing whitespace, computed when breaking lines.
+    pub trailing_whitespace_advance: f32,
     pub clusters: (u32, u32),
     pub ascent: f32,
     pub descent: f32,
     pub leading: f32,
     pub strikeout_offset: f32,
     pub strikeout_size: f32,
+    pub cap_height: f32,
     pub advance: f32,
+    pub synthesis: RunSynthesis,
+    /// Font was picked by fallback, only recorded with a fallback trace.
+    pub fallback: bool,
 }
 
 #[derive(Clone, Debug, Default)]
@@ -211,6 +241,16 @@ pub struct LayoutData {
     pub runs: Vec<RunData>,
     /// Last shaped span.
     pub last_span: usize,
+    /// Minimum height of a line, set from the layout context.
+    pub min_line_height: Option<f32>,
+    /// Multiplier of decoration thicknesses, set from the layout context.
+    pub decoration_thickness: Option<f32>,
+    /// Added to underline offsets, set from the layout context.
+    pub underline_offset_adjust: f32,
+    /// Color override of `.notdef` clusters, set from the layout context.
+    pub notdef_color: Option<[f32; 4]>,
+    /// Direction lines advance and stack in, set from the layout context.
+    pub layout_direction: LayoutDirection,
 }
 
 impl LayoutData {
@@ -221,6 +261,7 @@ impl LayoutData {
         self.clusters.clear();
         self.detailed_clusters.clear();
         self.runs.clear();
+        self.last_span = 0;
     }
 }
 
@@ -251,6 +292,8 @@ pub struct LineLayoutData {
     pub lines: Vec<LineData>,
     pub runs: Vec<RunData>,
     pub clusters: Vec<(u32, f32)>,
+    /// Visual index for each logical cluster index.
+    pub visual_clusters: Vec<u32>,
 }
 
 impl LineLayoutData {
@@ -259,13 +302,34 @@ impl LineLayoutData {
         self.lines.clear();
         self.runs.clear();
         self.clusters.clear();
+        self.visual_clusters.clear();
+    }
+
+    /// Rebuilds the logical to visual cluster map from `clusters`.
+    pub fn build_visual_clusters(&mut self, len: usize) {
+        self.visual_clusters.clear();
+        self.visual_clusters.resize(len, 0);
+        for (i, cluster) in self.clusters.iter().enumerate().rev() {
+            if let Some(visual) = self.visual_clusters.get_mut(cluster.0 as usize) {
+                *visual = i as u32;
+            }
+        }
     }
 
     #[inline]
     pub fn run_index_for_cluster(&self, cluster: u32) -> Option<usize> {
-        for (i, run) in self.runs.iter().enumerate() {
-            if cluster >= run.clusters.0 && cluster < run.clusters.1 {
-                return Some(i);
+        // Lines are stored in cluster order but runs inside a line are
+        // in visual order, so binary search the line and then scan its
+        // few runs.
+        let line = self
+            .lines
+            .partition_point(|line| line.clusters.1 <= cluster);
+        if let Some(line) = self.lines.get(line) {
+            for i in make_range(line.runs) {
+                let run = &self.runs[i];
+                if cluster >= run.clusters.0 && cluster < run.clusters.1 {
+                    return Some(i);
+                }
             }
         }
         self.runs.len().checked_sub(1)
@@ -288,13 +352,10 @@ impl LineLayoutData {
 
     #[inline]
     pub fn logical_to_visual(&self, cluster: u32) -> u32 {
-        // FIXME: linear search
-        for (i, x) in self.clusters.iter().enumerate() {
-            if x.0 == cluster {
-                return i as u32;
-            }
-        }
-        0
+        self.visual_clusters
+            .get(cluster as usize)
+            .copied()
+            .unwrap_or(0)
     }
 
     pub fn visual_to_logical(&self, cluster: u32) -> u32 {
@@ -313,3 +374,63 @@ impl LineLayoutData {
             .unwrap_or(false)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn linear_run_index_for_cluster(
+        data: &LineLayoutData,
+        cluster: u32,
+    ) -> Option<usize> {
+        for (i, run) in data.runs.iter().enumerate() {
+            if cluster >= run.clusters.0 && cluster < run.clusters.1 {
+                return Some(i);
+            }
+        }
+        data.runs.len().checked_sub(1)
+    }
+
+    #[test]
+    fn test_run_index_for_cluster_matches_linear_scan() {
+        let mut seed: u32 = 0x9e37_79b9;
+        let mut next = move |max: u32| {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (seed >> 16) % max
+        };
+
+        for _ in 0..64 {
+            let mut data = LineLayoutData::default();
+            let mut cluster = 0;
+            for _ in 0..next(8) + 1 {
+                let line_start = cluster;
+                let runs_start = data.runs.len() as u32;
+                for _ in 0..next(5) + 1 {
+                    let len = next(6) + 1;
+                    data.runs.push(RunData {
+                        clusters: (cluster, cluster + len),
+                        ..RunData::default()
+                    });
+                    cluster += len;
+                }
+                let runs_end = data.runs.len() as u32;
+                // Shuffle like a bidi reorder would.
+                if next(2) == 1 {
+                    data.runs[runs_start as usize..runs_end as usize].reverse();
+                }
+                data.lines.push(LineData {
+                    runs: (runs_start, runs_end),
+                    clusters: (line_start, cluster),
+                    ..LineData::default()
+                });
+            }
+
+            for query in 0..cluster + 4 {
+                assert_eq!(
+                    data.run_index_for_cluster(query),
+                    linear_run_index_for_cluster(&data, query)
+                );
+            }
+        }
+    }
+}
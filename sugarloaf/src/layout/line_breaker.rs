@@ -6,8 +6,10 @@
 // line_breaker.rs was originally retired from dfrg/swash_demo licensed under MIT
 // https://github.com/dfrg/swash_demo/blob/master/LICENSE
 
+use super::bidi::reorder;
 use super::layout_data::*;
 use super::render_data::*;
+use swash::text::cluster::Boundary;
 
 /// Alignment of a paragraph.
 #[derive(Copy, Default, Clone, PartialEq, Eq, Debug)]
@@ -15,11 +17,40 @@ use super::render_data::*;
 pub enum Alignment {
     #[default]
     Start,
-    Middle,
+    Center,
     End,
+    /// Stretches inner spaces so the line fills `max_advance`. Lines
+    /// ending in a mandatory break keep the start alignment.
+    Justify,
+}
+
+/// Direction in which lines advance and stack.
+#[derive(Copy, Default, Clone, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum LayoutDirection {
+    /// Lines advance along x and stack top to bottom.
+    #[default]
+    Horizontal,
+    /// Lines advance along y and stack right to left, e.g. for CJK
+    /// vertical text.
+    VerticalRL,
+    /// Lines advance along y and stack left to right.
+    VerticalLR,
+}
+
+impl LayoutDirection {
+    /// Returns true if lines advance along y.
+    #[inline]
+    pub fn is_vertical(self) -> bool {
+        self != LayoutDirection::Horizontal
+    }
 }
 
 /// Line breaking support for a paragraph.
+///
+/// Advances are accumulated in `f64` and only narrowed to `f32` when a
+/// cluster position is stored, so a position is within one `f32` ulp
+/// of the exact sum of the advances before it, whatever the line length.
 pub struct BreakLines<'a> {
     layout: &'a mut LayoutData,
     lines: &'a mut LineLayoutData,
@@ -98,25 +129,165 @@ impl<'a> BreakLines<'a> {
         self.finish();
     }
 
+    /// Breaks the paragraph into lines no wider than `max_advance`.
+    ///
+    /// Lines wrap at the last break opportunity that fits and fall back
+    /// to an emergency break when a single word overflows the line.
+    /// Mandatory newlines and paragraph lines are always honored.
+    pub fn break_lines(&'a mut self, max_advance: f32, alignment: Alignment) {
+        let run_len = self.layout.runs.len();
+        let mut i = 0;
+        let mut j = self
+            .layout
+            .runs
+            .first()
+            .map(|run| run.clusters.0)
+            .unwrap_or(0);
+        self.state.line.runs = (0, 0);
+        self.state.line.clusters = (j, j);
+        self.state.line.x = 0.;
+        // Run index, cluster index and advance right before the last
+        // break opportunity found in the current line.
+        let mut last_break: Option<(usize, u32, f64)> = None;
+
+        'runs: while i < run_len {
+            let run = self.layout.runs[i];
+            while j < run.clusters.1 {
+                let cluster = self.layout.clusters[j as usize];
+                let line_start = self.state.line.clusters.0;
+                if j > line_start && cluster.info.boundary() == Boundary::Line {
+                    last_break = Some((i, j, self.state.line.x));
+                }
+
+                let advance = cluster.advance(
+                    &self.layout.detailed_clusters,
+                    &self.layout.glyphs,
+                    &self.layout.detailed_glyphs,
+                );
+                // Trailing whitespace hangs past the edge and ligatures
+                // are never split.
+                if self.state.line.x + advance as f64 > max_advance as f64
+                    && j > line_start
+                    && !cluster.info.is_whitespace()
+                    && !cluster.is_continuation()
+                {
+                    let (run_index, cluster_index, x) =
+                        last_break.unwrap_or((i, j, self.state.line.x));
+                    self.commit_until(
+                        run_index,
+                        cluster_index,
+                        x,
+                        Some(max_advance),
+                        alignment,
+                        false,
+                    );
+                    last_break = None;
+                    i = run_index;
+                    j = cluster_index;
+                    continue 'runs;
+                }
+
+                self.state.line.x += advance as f64;
+                j += 1;
+                if cluster.is_newline() {
+                    let x = self.state.line.x;
+                    self.commit_until(i, j, x, Some(max_advance), alignment, true);
+                    last_break = None;
+                }
+            }
+
+            let next_line = self.layout.runs.get(i + 1).map(|next| next.line);
+            if next_line != Some(run.line) && self.state.line.clusters.0 < run.clusters.1
+            {
+                let x = self.state.line.x;
+                self.commit_until(
+                    i,
+                    run.clusters.1,
+                    x,
+                    Some(max_advance),
+                    alignment,
+                    true,
+                );
+                last_break = None;
+            }
+
+            i += 1;
+            if let Some(next) = self.layout.runs.get(i) {
+                j = next.clusters.0;
+            }
+        }
+
+        self.finish();
+    }
+
+    /// Commits the current line right before `cluster` of the run at
+    /// `run` and starts the next line from there.
+    #[inline]
+    fn commit_until(
+        &mut self,
+        run: usize,
+        cluster: u32,
+        x: f64,
+        max_advance: Option<f32>,
+        alignment: Alignment,
+        explicit: bool,
+    ) {
+        self.prev_state = Some(self.state);
+        self.state.line.runs.1 = run as u32 + 1;
+        self.state.line.clusters.1 = cluster;
+        self.state.line.x = x;
+        if commit_line(
+            self.layout,
+            self.lines,
+            &mut self.state.line,
+            max_advance,
+            alignment,
+            explicit,
+        ) {
+            self.state.runs = self.lines.runs.len();
+            self.state.lines = self.lines.lines.len();
+        }
+        self.state.line.runs.0 = run as u32;
+        self.state.line.clusters = (cluster, cluster);
+        self.state.line.x = 0.;
+    }
+
     /// Consumes the line breaker and finalizes all line computations.
     pub fn finish(&'a mut self) {
+        let layout = &self.layout;
+        let advance = |cluster: &ClusterData| {
+            cluster.advance(
+                &layout.detailed_clusters,
+                &layout.glyphs,
+                &layout.detailed_glyphs,
+            )
+        };
         for run in &mut self.lines.runs {
+            if let Some(decoration_thickness) = layout.decoration_thickness {
+                run.strikeout_size = (run.strikeout_size * decoration_thickness).max(1.);
+            }
+            if layout.underline_offset_adjust != 0. {
+                let offset = run.span.underline_offset.unwrap_or(0.);
+                run.span.underline_offset = Some(offset + layout.underline_offset_adjust);
+            }
             run.whitespace = true;
+            run.trailing_whitespace_advance = 0.;
             if run.level & 1 != 0 {
                 // RTL runs check for "trailing" whitespace at the front.
-                for cluster in self.layout.clusters[make_range(run.clusters)].iter() {
+                for cluster in layout.clusters[make_range(run.clusters)].iter() {
                     if cluster.info.is_whitespace() {
                         run.trailing_whitespace = true;
+                        run.trailing_whitespace_advance += advance(cluster);
                     } else {
                         run.whitespace = false;
                         break;
                     }
                 }
             } else {
-                for cluster in self.layout.clusters[make_range(run.clusters)].iter().rev()
-                {
+                for cluster in layout.clusters[make_range(run.clusters)].iter().rev() {
                     if cluster.info.is_whitespace() {
                         run.trailing_whitespace = true;
+                        run.trailing_whitespace_advance += advance(cluster);
                     } else {
                         run.whitespace = false;
                         break;
@@ -130,8 +301,13 @@ impl<'a> BreakLines<'a> {
             line.ascent = 0.;
             line.descent = 0.;
             line.leading = 0.;
-            let mut total_advance = 0.;
+            let mut total_advance: f64 = 0.;
+            let mut line_spacing: f32 = 0.;
             for run in self.lines.runs[make_range(line.runs)].iter() {
+                line.ascent = line.ascent.max(run.ascent);
+                line.descent = line.descent.max(run.descent);
+                line.leading = line.leading.max(run.leading);
+                line_spacing = line_spacing.max(run.span.line_spacing);
                 let r = Run::new(self.layout, run);
                 let rtl = run.level & 1 != 0;
                 let clusters = r.visual_clusters();
@@ -144,29 +320,64 @@ impl<'a> BreakLines<'a> {
                         run.clusters.0 + pos
                     };
                     pos += 1;
-                    self.lines.clusters.push((index, total_advance));
-                    total_advance += cluster.advance();
+                    self.lines.clusters.push((index, total_advance as f32));
+                    total_advance += cluster.advance() as f64;
                 }
             }
-            if line.alignment != Alignment::Start {
-                let trailing_space_advance =
-                    if line.clusters.0 != line.clusters.1 && line.clusters.1 > 0 {
-                        let (cluster_index, cluster_offset) =
-                            self.lines.clusters[line.clusters.1 as usize - 1];
-                        let cluster_data = self.layout.clusters[cluster_index as usize];
-                        if cluster_data.info.whitespace().is_space_or_nbsp() {
-                            total_advance - cluster_offset
-                        } else {
-                            0.
-                        }
-                    } else {
-                        0.
-                    };
+            let total_advance = total_advance as f32;
+            // Right-to-left paragraphs align from the opposite edge.
+            let rtl = self.lines.runs[make_range(line.runs)]
+                .iter()
+                .map(|run| run.level)
+                .min()
+                .unwrap_or(0)
+                & 1
+                != 0;
+            let alignment = match line.alignment {
+                Alignment::Justify if line.explicit_break => Alignment::Start,
+                Alignment::Start if rtl => Alignment::End,
+                Alignment::End if rtl => Alignment::Start,
+                alignment => alignment,
+            };
+            if alignment != Alignment::Start {
+                // Trailing whitespace hangs past the edge, so it doesn't
+                // count against `max_advance`.
+                let trailing_space_advance = self.lines.runs[make_range(line.runs)]
+                    .last()
+                    .map(|run| run.trailing_whitespace_advance)
+                    .unwrap_or(0.);
 
                 if let Some(max_advance) = line.max_advance {
                     let extra = max_advance - total_advance + trailing_space_advance;
-                    if extra > 0. {
-                        let offset = if line.alignment == Alignment::Middle {
+                    if extra > 0. && alignment == Alignment::Justify {
+                        let clusters =
+                            &mut self.lines.clusters[make_range(line.clusters)];
+                        let is_space = |index: u32| {
+                            self.layout.clusters[index as usize]
+                                .info
+                                .whitespace()
+                                .is_space_or_nbsp()
+                        };
+                        let inner_end = clusters
+                            .iter()
+                            .rposition(|cluster| !is_space(cluster.0))
+                            .unwrap_or(0);
+                        let spaces = clusters[..inner_end]
+                            .iter()
+                            .filter(|cluster| is_space(cluster.0))
+                            .count();
+                        if spaces > 0 {
+                            let per_space = extra / spaces as f32;
+                            let mut offset = 0.;
+                            for (i, cluster) in clusters.iter_mut().enumerate() {
+                                cluster.1 += offset;
+                                if i < inner_end && is_space(cluster.0) {
+                                    offset += per_space;
+                                }
+                            }
+                        }
+                    } else if extra > 0. {
+                        let offset = if alignment == Alignment::Center {
                             extra * 0.5
                         } else {
                             extra
@@ -184,26 +395,62 @@ impl<'a> BreakLines<'a> {
                 self.lines.runs[line.runs.1 as usize - 1].trailing_whitespace;
 
             if self.lines_uses_same_height {
+                // Metrics come from the first run so fallback fonts don't
+                // change the line height, however the largest line spacing
+                // on the line still applies.
                 let run = &self.lines.runs[line.runs.0 as usize];
-                line.ascent = run.ascent;
-                line.descent = run.descent;
-                line.leading = run.leading;
+                let factor = if run.span.line_spacing > 0. {
+                    line_spacing / run.span.line_spacing
+                } else {
+                    1.
+                };
+                line.ascent = run.ascent * factor;
+                line.descent = run.descent * factor;
+                line.leading = run.leading * factor;
+            }
+            // Superscripts and subscripts grow the line to fit.
+            for run in &self.lines.runs[make_range(line.runs)] {
+                let shift = run.span.baseline_shift;
+                if shift != 0. {
+                    line.ascent = line.ascent.max(run.ascent + shift);
+                    line.descent = line.descent.max(run.descent - shift);
+                }
             }
 
             line.ascent = line.ascent.round();
             line.descent = line.descent.round();
             line.leading = (line.leading * 0.5).round() * 2.;
+            if let Some(min_line_height) = self.layout.min_line_height {
+                let extra = min_line_height - (line.ascent + line.descent + line.leading);
+                if extra > 0. {
+                    // Split the padding to keep the text centered.
+                    let above = (extra * 0.5).round();
+                    line.ascent += above;
+                    line.descent += extra - above;
+                }
+            }
             let above = (line.ascent + line.leading * 0.5).round();
             let below = (line.descent + line.leading * 0.5).round();
             line.baseline = y + above;
             y = line.baseline + below;
         }
+        // Vertical lines are columns, and `baseline` is the x position of
+        // the column. Right to left columns are mirrored so the first one
+        // is at the right edge of the paragraph.
+        if self.layout.layout_direction == LayoutDirection::VerticalRL {
+            for line in &mut self.lines.lines {
+                let above = (line.ascent + line.leading * 0.5).round();
+                let below = (line.descent + line.leading * 0.5).round();
+                line.baseline = y - line.baseline - below + above;
+            }
+        }
+        self.lines.build_visual_clusters(self.layout.clusters.len());
     }
 }
 
 #[derive(Copy, Clone, Default)]
 struct LineState {
-    x: f32,
+    x: f64,
     runs: (u32, u32),
     clusters: (u32, u32),
 }
@@ -251,10 +498,20 @@ fn commit_line(
     if runs_start == runs_end {
         return false;
     }
+    // Reorder runs of mixed direction into visual order.
+    let line_runs = &mut lines.runs[make_range((runs_start, runs_end))];
+    if line_runs.iter().any(|run| run.level != 0) {
+        let mut order = vec![0; line_runs.len()];
+        reorder(&mut order, |i| line_runs[i].level);
+        let logical = line_runs.to_vec();
+        for (run, index) in line_runs.iter_mut().zip(order) {
+            *run = logical[index];
+        }
+    }
     let line = LineData {
         runs: (runs_start, runs_end),
         clusters: state.clusters,
-        width: state.x,
+        width: state.x as f32,
         max_advance,
         alignment,
         explicit_break: explicit,
@@ -266,3 +523,324 @@ fn commit_line(
     state.runs.0 = state.runs.1 - 1;
     true
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::font::FontLibrary;
+    use crate::layout::{Direction, FragmentStyle, LayoutContext, RenderData};
+
+    fn shape(text: &str) -> RenderData {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let mut render_data = RenderData::new();
+        let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+        lb.add_text(text, FragmentStyle::default());
+        lb.build_into(&mut render_data);
+        render_data
+    }
+
+    fn cell_advance(render_data: &RenderData) -> f32 {
+        let cluster = render_data.data.clusters[0];
+        cluster.advance(
+            &render_data.data.detailed_clusters,
+            &render_data.data.glyphs,
+            &render_data.data.detailed_glyphs,
+        )
+    }
+
+    fn line_texts(render_data: &RenderData, text: &str) -> Vec<String> {
+        render_data
+            .lines()
+            .map(|line| {
+                line.runs()
+                    .flat_map(|run| run.clusters())
+                    // Skip the trailing space `resolve` appends to the text.
+                    .filter(|cluster| cluster.range().end <= text.len())
+                    .map(|cluster| text[cluster.range()].to_owned())
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn render_data_with_levels(levels: &[u8]) -> RenderData {
+        let mut render_data = RenderData::default();
+        for (i, level) in levels.iter().enumerate() {
+            render_data.data.clusters.push(ClusterData {
+                info: Default::default(),
+                flags: CLUSTER_EMPTY,
+                len: 1,
+                offset: i as u32,
+                glyphs: 0,
+            });
+            render_data.data.runs.push(RunData {
+                level: *level,
+                clusters: (i as u32, i as u32 + 1),
+                ..RunData::default()
+            });
+        }
+        render_data
+    }
+
+    #[test]
+    fn test_commit_line_reorders_rtl_runs() {
+        // "abc אבג דהו xyz" => LTR run, two RTL runs, LTR run.
+        let mut render_data = render_data_with_levels(&[0, 1, 1, 0]);
+        render_data
+            .break_lines()
+            .break_without_advance_or_alignment();
+
+        let order: Vec<u32> = render_data
+            .line_data
+            .runs
+            .iter()
+            .map(|run| run.clusters.0)
+            .collect();
+        assert_eq!(order, vec![0, 2, 1, 3]);
+        assert_eq!(render_data.line_data.logical_to_visual(1), 2);
+        assert_eq!(render_data.line_data.logical_to_visual(2), 1);
+        assert_eq!(render_data.line_data.visual_to_logical(1), 2);
+    }
+
+    #[test]
+    fn test_commit_line_keeps_ltr_order() {
+        let mut render_data = render_data_with_levels(&[0, 0, 0]);
+        render_data
+            .break_lines()
+            .break_without_advance_or_alignment();
+
+        let order: Vec<u32> = render_data
+            .line_data
+            .runs
+            .iter()
+            .map(|run| run.clusters.0)
+            .collect();
+        assert_eq!(order, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_break_lines_wraps_at_word_boundaries() {
+        let text = "aaa bbb ccc ddd";
+        let mut render_data = shape(text);
+        let max_advance = cell_advance(&render_data) * 8.5;
+        render_data
+            .break_lines()
+            .break_lines(max_advance, Alignment::Start);
+
+        let lines = line_texts(&render_data, text);
+        assert_eq!(lines, vec!["aaa bbb ", "ccc ddd"]);
+    }
+
+    #[test]
+    fn test_separators_are_explicit_breaks() {
+        for separator in ['\u{2028}', '\u{2029}'] {
+            let text = format!("aaa bbb{separator}cc");
+            let mut render_data = shape(&text);
+            let max_advance = cell_advance(&render_data) * 4.5;
+            render_data
+                .break_lines()
+                .break_lines(max_advance, Alignment::Justify);
+
+            let lines = line_texts(&render_data, &text);
+            assert_eq!(
+                lines,
+                vec!["aaa ", format!("bbb{separator}").as_str(), "cc"]
+            );
+            let explicit: Vec<bool> = render_data
+                .lines()
+                .map(|line| line.is_explicit_break())
+                .collect();
+            assert_eq!(explicit, vec![false, true, true]);
+        }
+    }
+
+    #[test]
+    fn test_break_lines_emergency_breaks_long_words() {
+        let text = "abcdefghij";
+        let mut render_data = shape(text);
+        let max_advance = cell_advance(&render_data) * 4.5;
+        render_data
+            .break_lines()
+            .break_lines(max_advance, Alignment::Start);
+
+        let lines = line_texts(&render_data, text);
+        assert_eq!(lines, vec!["abcd", "efgh", "ij"]);
+    }
+
+    #[test]
+    fn test_break_lines_center_alignment() {
+        let mut render_data = shape("abc");
+        let max_advance = cell_advance(&render_data) * 10.;
+        render_data
+            .break_lines()
+            .break_lines(max_advance, Alignment::Center);
+
+        let line = render_data.lines().next().unwrap();
+        let width = line.advance();
+        assert_eq!(line.offset(), (max_advance - width) / 2.);
+    }
+
+    #[test]
+    fn test_break_lines_does_not_justify_last_line() {
+        let mut render_data = shape("aa bb");
+        let max_advance = cell_advance(&render_data) * 10.;
+        render_data
+            .break_lines()
+            .break_lines(max_advance, Alignment::Justify);
+
+        let line = render_data.lines().next().unwrap();
+        assert_eq!(line.offset(), 0.);
+        let positions: Vec<f32> = render_data
+            .line_data
+            .clusters
+            .iter()
+            .map(|cluster| cluster.1)
+            .collect();
+        let cell = cell_advance(&render_data);
+        assert_eq!(positions[3], cell * 3.);
+    }
+
+    #[test]
+    fn test_trailing_whitespace_does_not_overflow_line() {
+        let text = "aaa    bbb";
+        let mut render_data = shape(text);
+        let cell = cell_advance(&render_data);
+        let max_advance = cell * 5.5;
+        render_data
+            .break_lines()
+            .break_lines(max_advance, Alignment::End);
+
+        let lines = line_texts(&render_data, text);
+        assert_eq!(lines, vec!["aaa    ", "bbb"]);
+
+        let line = render_data.lines().next().unwrap();
+        let run = line.runs().last().unwrap();
+        assert_eq!(run.trailing_whitespace_advance(), cell * 4.);
+        assert!(line.advance() > max_advance);
+        // Only the visible text is aligned against the edge.
+        assert_eq!(line.offset(), max_advance - cell * 3.);
+    }
+
+    #[test]
+    fn test_logical_to_visual_matches_linear_scan() {
+        let levels: Vec<u8> = (0..10_000).map(|i| ((i / 3) % 2) as u8).collect();
+        let mut render_data = render_data_with_levels(&levels);
+        render_data
+            .break_lines()
+            .break_without_advance_or_alignment();
+
+        let line_data = &render_data.line_data;
+        for cluster in 0..levels.len() as u32 {
+            let expected = line_data
+                .clusters
+                .iter()
+                .position(|x| x.0 == cluster)
+                .unwrap_or(0) as u32;
+            let visual = line_data.logical_to_visual(cluster);
+            assert_eq!(visual, expected);
+            assert_eq!(line_data.visual_to_logical(visual), cluster);
+        }
+    }
+
+    #[test]
+    fn test_long_lines_do_not_drift() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let mut render_data = RenderData::new();
+        let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+        // 13px Cascadia cells are not a multiple of 1/64.
+        let style = FragmentStyle {
+            font_size: 13.,
+            ..FragmentStyle::default()
+        };
+        lb.add_text(&"a".repeat(500), style);
+        lb.build_into(&mut render_data);
+        render_data
+            .break_lines()
+            .break_without_advance_or_alignment();
+
+        let exact = {
+            let library = font_library.inner.read().unwrap();
+            let font = library[crate::font::FONT_ID_REGULAR].as_ref();
+            let glyph = font.charmap().map('a');
+            font.glyph_metrics(&[]).scale(13.).advance_width(glyph) as f64
+        };
+        assert_ne!((exact * 64.).fract(), 0.);
+        // Fractional advances still fit in simple glyphs.
+        assert!(render_data.data.detailed_glyphs.is_empty());
+        let last = render_data.line_data.clusters[499].1 as f64;
+        assert!((last - exact * 499.).abs() < 0.5);
+    }
+
+    #[test]
+    fn test_vertical_layout_advances_along_y() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let layout = |lcx: &mut LayoutContext, direction| {
+            lcx.set_layout_direction(direction);
+            let mut render_data = RenderData::new();
+            let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+            lb.add_text("ab", FragmentStyle::default());
+            lb.new_line();
+            lb.add_text("cd", FragmentStyle::default());
+            lb.build_into(&mut render_data);
+            render_data
+                .break_lines()
+                .break_without_advance_or_alignment();
+            render_data
+        };
+
+        let horizontal = layout(&mut lcx, LayoutDirection::Horizontal);
+        let line = horizontal.lines().next().unwrap();
+        let advance = line.advance();
+        assert!(advance > 0.);
+        let [x, y] = line.pen_position(advance);
+        assert_eq!(x, line.offset() + advance);
+        assert_eq!(y, line.baseline());
+
+        let vertical = layout(&mut lcx, LayoutDirection::VerticalRL);
+        let columns: Vec<_> = vertical.lines().collect();
+        assert_eq!(columns.len(), 2);
+        let [x, y] = columns[0].pen_position(advance);
+        assert_eq!(x, columns[0].baseline());
+        assert_eq!(y, columns[0].offset() + advance);
+        // Columns stack from the right edge.
+        assert!(columns[0].baseline() > columns[1].baseline());
+        assert_eq!(
+            columns[0].baseline() - columns[1].baseline(),
+            columns[1].size()
+        );
+
+        let vertical = layout(&mut lcx, LayoutDirection::VerticalLR);
+        let columns: Vec<_> = vertical.lines().collect();
+        assert!(columns[0].baseline() < columns[1].baseline());
+    }
+
+    #[test]
+    fn test_min_line_height_pads_short_lines() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let line_size = |lcx: &mut LayoutContext, min_line_height| {
+            lcx.set_min_line_height(min_line_height);
+            let mut render_data = RenderData::new();
+            let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+            lb.add_text("abc", FragmentStyle::default());
+            lb.build_into(&mut render_data);
+            render_data
+                .break_lines()
+                .break_without_advance_or_alignment();
+            let line = render_data.lines().next().unwrap();
+            (line.size(), line.baseline())
+        };
+
+        let (natural, natural_baseline) = line_size(&mut lcx, None);
+        assert!(natural < 30.);
+        let (padded, padded_baseline) = line_size(&mut lcx, Some(30.));
+        assert_eq!(padded, 30.);
+        // Text stays centered in the extra space.
+        let shift = padded_baseline - natural_baseline;
+        assert!((shift - (30. - natural) * 0.5).abs() <= 0.5);
+        // Taller natural lines are kept.
+        assert_eq!(line_size(&mut lcx, Some(natural - 4.)).0, natural);
+    }
+}
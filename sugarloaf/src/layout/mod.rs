@@ -13,13 +13,15 @@ mod bidi;
 mod builder;
 mod builder_data;
 mod content;
+mod incremental;
 mod layout_data;
 mod line_breaker;
 mod render_data;
 mod span_style;
 
-pub use content::{Content, ContentBuilder};
-pub use render_data::RenderData;
+pub use content::{Content, ContentBuilder, LineDiff};
+pub use incremental::IncrementalLayout;
+pub use render_data::{RenderData, RenderDataSnapshot};
 
 #[doc(inline)]
 pub use swash::text::Language;
@@ -29,9 +31,13 @@ pub mod iter {
     pub use super::render_data::{Clusters, Glyphs, Lines, Runs};
 }
 
-pub use builder::{LayoutContext, ParagraphBuilder};
-pub use line_breaker::{Alignment, BreakLines};
-pub use render_data::{Cluster, Glyph, Line, Run};
+pub use builder::{
+    CacheStats, CellMetrics, LayoutContext, ParagraphBuilder, DEFAULT_RUN_CACHE_CAPACITY,
+};
+pub use builder_data::{ControlCharMode, TabRender};
+pub use layout_data::RunSynthesis;
+pub use line_breaker::{Alignment, BreakLines, LayoutDirection};
+pub use render_data::{Cluster, Glyph, HitTestResult, Line, LineMetrics, Run};
 pub use span_style::*;
 
 /// Largest allowable span or fragment identifier.
@@ -14,14 +14,17 @@ use super::layout_data::*;
 use super::line_breaker::BreakLines;
 use super::Direction;
 use crate::font::{
-    Style, Weight, FONT_ID_BOLD, FONT_ID_BOLD_ITALIC, FONT_ID_ITALIC, FONT_ID_REGULAR,
+    FontLibrary, Style, Weight, FONT_ID_BOLD, FONT_ID_BOLD_ITALIC, FONT_ID_ITALIC,
+    FONT_ID_REGULAR,
 };
 use crate::layout::FragmentStyle;
-use crate::sugarloaf::primitives::SugarCursor;
+use crate::sugarloaf::primitives::{StrikethroughInfo, SugarCursor};
+use crate::SugarGraphic;
 use core::iter::DoubleEndedIterator;
 use core::ops::Range;
+use smallvec::SmallVec;
 use swash::shape::{cluster::Glyph as ShapedGlyph, Shaper};
-use swash::text::cluster::{Boundary, ClusterInfo};
+use swash::text::cluster::{Boundary, ClusterInfo, Whitespace};
 use swash::{GlyphId, NormalizedCoord};
 
 /// Collection of text, organized into lines, runs and clusters.
@@ -31,9 +34,35 @@ pub struct RenderData {
     last_line: u32,
     pub last_cached_run: RunCacheEntry,
     pub line_data: LineLayoutData,
+    /// Whether each built line was laid out from the run cache, starting
+    /// at the first line of the range passed to `build_into_range`.
+    pub line_from_cache: Vec<bool>,
+}
+
+/// Owned copy of a laid out paragraph, see `RenderData::snapshot`.
+#[derive(Clone, Debug, Default)]
+pub struct RenderDataSnapshot {
+    data: LayoutData,
+    line_data: LineLayoutData,
 }
 
 impl RenderData {
+    /// Copies the shaped and broken layout, e.g. to undo an edit without
+    /// shaping again. Every buffer is cloned, so the cost grows with the
+    /// amount of glyphs and clusters.
+    pub fn snapshot(&self) -> RenderDataSnapshot {
+        RenderDataSnapshot {
+            data: self.data.clone(),
+            line_data: self.line_data.clone(),
+        }
+    }
+
+    /// Replaces the layout with a snapshot taken by `snapshot`.
+    pub fn restore(&mut self, snapshot: RenderDataSnapshot) {
+        self.data = snapshot.data;
+        self.line_data = snapshot.line_data;
+    }
+
     #[inline]
     pub fn is_empty(&self) -> bool {
         self.line_data.lines.is_empty()
@@ -67,11 +96,135 @@ impl RenderData {
         }
     }
 
-    /// Clears the paragraph.
+    /// Returns the run containing the cluster at index `cluster`.
+    pub fn run_for_cluster(&self, cluster: u32) -> Option<Run> {
+        if cluster as usize >= self.data.clusters.len() {
+            return None;
+        }
+        self.line_data
+            .run_data_for_cluster(cluster)
+            .map(|run| Run::new(&self.data, run))
+    }
+
+    /// Returns the line containing the cluster at index `cluster`.
+    pub fn line_for_cluster(&self, cluster: u32) -> Option<Line> {
+        if cluster as usize >= self.data.clusters.len() {
+            return None;
+        }
+        let index = self.line_data.line_index_for_cluster(cluster);
+        self.line_data.lines.get(index).map(|line| Line {
+            layout: &self.data,
+            line_layout: &self.line_data,
+            line,
+        })
+    }
+
+    /// Returns an iterator over the runs of every line, line by line and
+    /// in visual order within a line. See `Run::line` to group them.
+    #[inline]
+    pub fn runs(&self) -> Runs {
+        Runs {
+            layout: &self.data,
+            iter: self.line_data.runs.iter(),
+        }
+    }
+
+    /// Returns the vertical metrics of every line, in paragraph space.
+    pub fn line_metrics(&self) -> impl Iterator<Item = LineMetrics> + '_ {
+        self.lines().map(|line| LineMetrics {
+            baseline: line.baseline(),
+            ascent: line.ascent(),
+            descent: line.descent(),
+            leading: line.leading(),
+            top: line.top(),
+            bottom: line.top() + line.size(),
+        })
+    }
+
+    /// Returns the width of the widest line and the height of all lines
+    /// stacked, from the top of the first line to the bottom of the last.
+    pub fn size(&self) -> (f32, f32) {
+        self.lines().fold((0., 0.), |(width, height), line| {
+            (
+                f32::max(width, line.offset() + line.advance()),
+                f32::max(height, line.top() + line.size()),
+            )
+        })
+    }
+
+    /// Returns the cap height of `line` above its baseline, the tallest
+    /// of its runs, e.g. to center an icon against the text of a line.
+    /// Returns `None` for lines that don't exist or lines were not broken.
+    pub fn cap_height(&self, line: usize) -> Option<f32> {
+        self.lines()
+            .nth(line)?
+            .runs()
+            .map(|run| run.cap_height())
+            .reduce(f32::max)
+    }
+
+    /// Returns the source byte ranges of the clusters shaped to the
+    /// `.notdef` glyph, in source order with adjacent ranges merged.
+    pub fn missing_ranges(&self) -> Vec<Range<usize>> {
+        let mut ranges: Vec<Range<usize>> = self
+            .lines()
+            .flat_map(|line| line.runs())
+            .flat_map(|run| run.clusters())
+            .filter(|cluster| cluster.is_notdef())
+            .map(|cluster| cluster.range())
+            .collect();
+        ranges.sort_by_key(|range| range.start);
+        let mut merged: Vec<Range<usize>> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if last.end == range.start => last.end = range.end,
+                _ => merged.push(range),
+            }
+        }
+        merged
+    }
+
+    /// Returns the source byte range of the word around `byte_offset`,
+    /// e.g. to select a word on double click. Words are delimited by the
+    /// word boundaries of the clusters, so punctuation is a word of its
+    /// own and every ideograph is a separate word. Returns an empty range
+    /// at `byte_offset` if no cluster contains it.
+    pub fn word_range_at(&self, byte_offset: usize) -> Range<usize> {
+        let clusters = &self.data.clusters;
+        let contains = |cluster: &ClusterData| {
+            let start = cluster.offset as usize;
+            (start..start + cluster.len as usize).contains(&byte_offset)
+        };
+        let index = match clusters.iter().position(contains) {
+            Some(index) => index,
+            None => return byte_offset..byte_offset,
+        };
+
+        // Ligature continuations carry no boundary, so they never split.
+        let is_boundary =
+            |cluster: &ClusterData| cluster.info.boundary() != Boundary::None;
+        let start = clusters[..=index]
+            .iter()
+            .rposition(is_boundary)
+            .unwrap_or(0);
+        let end = clusters[index + 1..]
+            .iter()
+            .position(is_boundary)
+            .map(|position| index + 1 + position)
+            .unwrap_or(clusters.len());
+        let last = &clusters[end - 1];
+        clusters[start].offset as usize..last.offset as usize + last.len as usize
+    }
+
+    /// Clears the paragraph, including the state of the line being
+    /// cached, so it can be reused for the next layout.
     #[inline]
     pub fn clear(&mut self) {
         self.data.clear();
         self.line_data.clear();
+        self.last_line = 0;
+        self.last_cached_run.runs.clear();
+        self.line_from_cache.clear();
     }
 }
 
@@ -84,9 +237,12 @@ pub struct CachedClusterData {
     /// Offset of the cluster in the source text.
     pub offset: u32,
     /// Depending on `flags`, either an index into `glyphs` or an index
-    /// into `detailed_clusters`
-    pub glyphs: Vec<GlyphData>,
-    pub details: Vec<DetailedClusterData>,
+    /// into `detailed_clusters`. Almost every cluster has a single glyph
+    /// and no details, so both are kept inline.
+    pub glyphs: SmallVec<[GlyphData; 1]>,
+    pub details: SmallVec<[DetailedClusterData; 1]>,
+    /// Detailed glyphs referenced by `glyphs`, in order.
+    pub detailed_glyphs: Vec<Glyph>,
 }
 
 #[derive(Debug, Clone)]
@@ -105,20 +261,54 @@ pub struct CachedRunData {
     pub leading: f32,
     pub strikeout_offset: f32,
     pub strikeout_size: f32,
+    pub cap_height: f32,
     pub advance: f32,
+    pub synthesis: RunSynthesis,
+    pub fallback: bool,
 }
 
 #[derive(Clone, Default, Debug)]
 pub struct RunCacheEntry {
     pub runs: Vec<CachedRunData>,
+    /// Builder scale the runs were shaped at.
+    pub scale: f32,
+}
+
+impl RunCacheEntry {
+    /// Returns true if every cluster carries the glyphs and details its
+    /// flags refer to, so the indexes rebuilt from it are in bounds.
+    pub fn is_valid(&self) -> bool {
+        self.runs
+            .iter()
+            .flat_map(|run| &run.clusters)
+            .all(|cluster| {
+                let detailed = cluster.flags & CLUSTER_DETAILED != 0;
+                let indexed = cluster.flags & (CLUSTER_EMPTY | CLUSTER_CONTINUATION) == 0;
+                let detailed_glyphs = cluster
+                    .glyphs
+                    .iter()
+                    .filter(|glyph| !glyph.is_simple())
+                    .count();
+                detailed == (cluster.details.len() == 1)
+                    && (detailed || !indexed || cluster.glyphs.len() == 1)
+                    && detailed_glyphs == cluster.detailed_glyphs.len()
+            })
+    }
 }
 
 impl RenderData {
+    /// Appends the runs of a cached line. Returns false, leaving the
+    /// layout untouched, if the entry is inconsistent, e.g. it was built
+    /// under a different font set.
     pub(super) fn push_run_from_cached_line(
         &mut self,
         cached_entry: &RunCacheEntry,
         line: u32,
-    ) {
+    ) -> bool {
+        if !cached_entry.is_valid() {
+            return false;
+        }
+
         // Every time a line is cached we need to rebuild the indexes
         // so RunData, Clusters, DetailedClusterData and Glyphs need to be
         // pointed correctly across each other otherwise will lead to panic
@@ -130,8 +320,19 @@ impl RenderData {
             let clusters_start = self.data.clusters.len() as u32;
             for cached_cluster in &cached_run.clusters {
                 let mut glyphs_start = self.data.glyphs.len() as u32;
+                let mut detailed_glyphs = cached_cluster.detailed_glyphs.iter();
                 for glyph_data in &cached_cluster.glyphs {
-                    self.data.glyphs.push(*glyph_data);
+                    if glyph_data.is_simple() {
+                        self.data.glyphs.push(*glyph_data);
+                    } else if let Some(glyph) = detailed_glyphs.next() {
+                        // Detailed glyphs are re-indexed like clusters.
+                        let detail_index = self.data.detailed_glyphs.len() as u64;
+                        self.data.detailed_glyphs.push(*glyph);
+                        self.data.glyphs.push(GlyphData {
+                            data: GLYPH_DETAILED | detail_index,
+                            size: glyph_data.size,
+                        });
+                    }
                 }
                 let glyphs_end = self.data.glyphs.len() as u32;
 
@@ -167,16 +368,21 @@ impl RenderData {
                 level: cached_run.level,
                 whitespace: false,
                 trailing_whitespace: false,
+                trailing_whitespace_advance: 0.,
                 ascent: cached_run.ascent,
                 descent: cached_run.descent,
                 leading: cached_run.leading,
                 strikeout_offset: cached_run.strikeout_offset,
                 strikeout_size: cached_run.strikeout_size,
+                cap_height: cached_run.cap_height,
                 advance: cached_run.advance,
+                synthesis: cached_run.synthesis,
+                fallback: cached_run.fallback,
             });
         }
 
         self.data.last_span = 0;
+        true
     }
 
     pub(super) fn push_run(
@@ -187,6 +393,8 @@ impl RenderData {
         level: u8,
         line: u32,
         shaper: Shaper<'_>,
+        fallback: bool,
+        synthesis: impl Fn(&FragmentStyle) -> RunSynthesis,
     ) {
         // In case is a new line,
         // then needs to recompute the span index again
@@ -206,7 +414,6 @@ impl RenderData {
 
         let mut advance = 0.;
         let mut last_span = self.data.last_span;
-        let mut span_data = &styles[last_span];
 
         shaper.shape_with(|c| {
             if c.info.boundary() == Boundary::Mandatory {
@@ -217,7 +424,7 @@ impl RenderData {
 
             let span = c.data;
             if span as usize != last_span {
-                span_data = &styles[last_span];
+                let span_data = &styles[last_span];
                 // Ensure that every run belongs to a single span.
                 let clusters_end = self.data.clusters.len() as u32;
                 if clusters_end != clusters_start {
@@ -230,13 +437,17 @@ impl RenderData {
                         level,
                         whitespace: false,
                         trailing_whitespace: false,
+                        trailing_whitespace_advance: 0.,
                         clusters: (clusters_start, clusters_end),
                         ascent: metrics.ascent * span_data.line_spacing,
                         descent: metrics.descent * span_data.line_spacing,
                         leading: metrics.leading * span_data.line_spacing,
                         strikeout_offset: metrics.strikeout_offset,
                         strikeout_size: metrics.stroke_size,
+                        cap_height: metrics.cap_height,
                         advance,
+                        synthesis: synthesis(span_data),
+                        fallback,
                     };
                     self.data.runs.push(run_data);
                     let mut owned_clusters = Vec::with_capacity(
@@ -245,7 +456,7 @@ impl RenderData {
                     for current_cluster in &self.data.clusters
                         [clusters_start as usize..clusters_end as usize]
                     {
-                        let mut detailed_clusters = Vec::with_capacity(2);
+                        let mut detailed_clusters = SmallVec::new();
                         let glyphs_data = if current_cluster.is_detailed() {
                             let detail = &self.data.detailed_clusters
                                 [current_cluster.glyphs as usize];
@@ -263,8 +474,15 @@ impl RenderData {
                             flags: current_cluster.flags,
                             len: current_cluster.len,
                             offset: current_cluster.offset,
-                            glyphs: glyphs_data.to_vec(),
+                            glyphs: SmallVec::from_slice(glyphs_data),
                             details: detailed_clusters,
+                            detailed_glyphs: glyphs_data
+                                .iter()
+                                .filter(|glyph| !glyph.is_simple())
+                                .map(|glyph| {
+                                    self.data.detailed_glyphs[glyph.detail_index()]
+                                })
+                                .collect(),
                         });
                     }
                     self.last_cached_run.runs.push(CachedRunData {
@@ -282,7 +500,10 @@ impl RenderData {
                         leading: metrics.leading * span_data.line_spacing,
                         strikeout_offset: metrics.strikeout_offset,
                         strikeout_size: metrics.stroke_size,
+                        cap_height: metrics.cap_height,
                         advance,
+                        synthesis: synthesis(span_data),
+                        fallback,
                     });
                     clusters_start = clusters_end;
                 }
@@ -290,9 +511,21 @@ impl RenderData {
             }
             let mut glyphs_start = self.data.glyphs.len() as u32;
             let mut cluster_advance = 0.;
+            let style = &styles[span as usize];
             for glyph in c.glyphs {
-                cluster_advance += glyph.advance;
-                self.push_glyph(glyph);
+                let mut glyph = *glyph;
+                // Positive shifts raise superscripts.
+                glyph.y += style.baseline_shift;
+                self.push_glyph(&glyph);
+                if !style.force_zero_advance {
+                    cluster_advance += glyph.advance;
+                } else if let Some(data) = self.data.glyphs.last_mut() {
+                    if data.is_simple() {
+                        data.clear_advance();
+                    } else {
+                        self.data.detailed_glyphs[data.detail_index()].advance = 0.;
+                    }
+                }
             }
             advance += cluster_advance;
             let mut component_advance = cluster_advance;
@@ -358,6 +591,7 @@ impl RenderData {
             return;
         }
         self.data.last_span = last_span;
+        let span_data = &styles[last_span];
         let run_data = RunData {
             span: styles[last_span],
             line,
@@ -367,13 +601,17 @@ impl RenderData {
             level,
             whitespace: false,
             trailing_whitespace: false,
+            trailing_whitespace_advance: 0.,
             clusters: (clusters_start, clusters_end),
             ascent: metrics.ascent * span_data.line_spacing,
             descent: metrics.descent * span_data.line_spacing,
             leading: metrics.leading * span_data.line_spacing,
             strikeout_offset: metrics.strikeout_offset,
             strikeout_size: metrics.stroke_size,
+            cap_height: metrics.cap_height,
             advance,
+            synthesis: synthesis(span_data),
+            fallback,
         };
         self.data.runs.push(run_data);
         let mut owned_clusters =
@@ -381,7 +619,7 @@ impl RenderData {
         for current_cluster in
             &self.data.clusters[clusters_start as usize..clusters_end as usize]
         {
-            let mut detailed_clusters = Vec::with_capacity(2);
+            let mut detailed_clusters = SmallVec::new();
             let glyphs_data = if current_cluster.is_detailed() {
                 let detail =
                     &self.data.detailed_clusters[current_cluster.glyphs as usize];
@@ -398,8 +636,13 @@ impl RenderData {
                 flags: current_cluster.flags,
                 len: current_cluster.len,
                 offset: current_cluster.offset,
-                glyphs: glyphs_data.to_vec(),
+                glyphs: SmallVec::from_slice(glyphs_data),
                 details: detailed_clusters,
+                detailed_glyphs: glyphs_data
+                    .iter()
+                    .filter(|glyph| !glyph.is_simple())
+                    .map(|glyph| self.data.detailed_glyphs[glyph.detail_index()])
+                    .collect(),
             });
         }
         self.last_cached_run.runs.push(CachedRunData {
@@ -417,26 +660,26 @@ impl RenderData {
             leading: metrics.leading * span_data.line_spacing,
             strikeout_offset: metrics.strikeout_offset,
             strikeout_size: metrics.stroke_size,
+            cap_height: metrics.cap_height,
             advance,
+            synthesis: synthesis(span_data),
+            fallback,
         });
     }
 
     #[inline]
     fn push_glyph(&mut self, glyph: &ShapedGlyph) {
-        const MAX_SIMPLE_ADVANCE: u32 = 0x7FFF;
         if glyph.x == 0. && glyph.y == 0. {
-            let packed_advance = (glyph.advance * 64.) as u32;
-            if packed_advance <= MAX_SIMPLE_ADVANCE {
-                // Simple glyph
-                self.data.glyphs.push(GlyphData {
-                    data: glyph.id as u32 | (packed_advance << 16),
-                    size: glyph.data as usize,
-                });
-                return;
-            }
+            // Simple glyph, the advance is packed exactly.
+            self.data.glyphs.push(GlyphData::simple(
+                glyph.id,
+                glyph.advance,
+                glyph.data as usize,
+            ));
+            return;
         }
         // Complex glyph
-        let detail_index = self.data.detailed_glyphs.len() as u32;
+        let detail_index = self.data.detailed_glyphs.len() as u64;
         self.data.detailed_glyphs.push(Glyph::new(glyph));
         self.data.glyphs.push(GlyphData {
             data: GLYPH_DETAILED | detail_index,
@@ -483,9 +726,173 @@ impl RenderData {
         }
     }
 
+    /// Rounds the advance of every cluster to a whole number of cells,
+    /// at least one for clusters with an advance. Ligature components
+    /// share the snapped advance of the whole ligature.
+    pub(super) fn apply_monospace_snap(&mut self, cell_advance: f32) {
+        let data = &mut self.data;
+        for run in &mut data.runs {
+            for index in make_range(run.clusters) {
+                let cluster = data.clusters[index];
+                if cluster.is_continuation() {
+                    continue;
+                }
+                let components = if cluster.is_ligature() {
+                    1 + data.clusters[index + 1..make_range(run.clusters).end]
+                        .iter()
+                        .take_while(|cluster| cluster.is_continuation())
+                        .count()
+                } else {
+                    1
+                };
+                let advance = cluster.advance(
+                    &data.detailed_clusters,
+                    &data.glyphs,
+                    &data.detailed_glyphs,
+                ) * components as f32;
+                if advance <= 0. {
+                    continue;
+                }
+                let snapped = (advance / cell_advance).round().max(1.) * cell_advance;
+                let delta = snapped - advance;
+                if delta == 0. {
+                    continue;
+                }
+
+                let last_glyph = if cluster.is_detailed() {
+                    let detail = &mut data.detailed_clusters[cluster.glyphs as usize];
+                    detail.advance = snapped / components as f32;
+                    make_range(detail.glyphs).last()
+                } else {
+                    Some(cluster.glyphs as usize)
+                };
+                for continuation in &mut data.clusters[index + 1..index + components] {
+                    continuation.glyphs = (snapped / components as f32).to_bits();
+                }
+                if let Some(glyph_index) = last_glyph {
+                    let glyph = data.glyphs[glyph_index];
+                    if glyph.is_simple() {
+                        let (id, glyph_advance) = glyph.simple_data();
+                        data.glyphs[glyph_index] =
+                            GlyphData::simple(id, glyph_advance + delta, glyph.size);
+                    } else {
+                        data.detailed_glyphs[glyph.detail_index()].advance += delta;
+                    }
+                }
+                run.advance += delta;
+            }
+        }
+    }
+
+    /// Widens tab clusters so the following cluster starts at the next
+    /// multiple of `tab_width` cells. Tabs keep a single cluster, so
+    /// offsets still map to the tab character.
+    pub(super) fn apply_tab_stops(
+        &mut self,
+        tab_width: u32,
+        mut cell_advance: impl FnMut(&RunData) -> f32,
+    ) {
+        if tab_width == 0 {
+            return;
+        }
+        let mut line = None;
+        let mut x = 0.;
+        for run in &mut self.data.runs {
+            if line != Some(run.line) {
+                line = Some(run.line);
+                x = 0.;
+            }
+            let mut stop = None;
+            let clusters = &mut self.data.clusters[make_range(run.clusters)];
+            for cluster in clusters {
+                let advance = cluster.advance(
+                    &self.data.detailed_clusters,
+                    &self.data.glyphs,
+                    &self.data.detailed_glyphs,
+                );
+                // Tabs removed by the shaper have no glyph to widen.
+                if cluster.info.whitespace() != Whitespace::Tab || cluster.is_empty() {
+                    x += advance;
+                    continue;
+                }
+                let stop =
+                    *stop.get_or_insert_with(|| tab_width as f32 * cell_advance(run));
+                if stop <= 0. {
+                    x += advance;
+                    continue;
+                }
+                let target = ((x / stop).floor() + 1.) * stop;
+                let spacing = target - x - advance;
+                if cluster.is_detailed() {
+                    self.data.detailed_clusters[cluster.glyphs as usize].advance +=
+                        spacing;
+                }
+                if let Some(g) = cluster
+                    .glyphs_mut(&self.data.detailed_clusters, &mut self.data.glyphs)
+                    .last_mut()
+                {
+                    if g.is_simple() {
+                        g.add_spacing(spacing);
+                    } else {
+                        self.data.detailed_glyphs[g.detail_index()].advance += spacing;
+                    }
+                    run.advance += spacing;
+                }
+                x = target;
+            }
+        }
+    }
+
+    /// Replaces the glyph of every tab cluster with the one returned by
+    /// `indicator` for the run font. The advance is left as is, so the
+    /// indicator is drawn at the start of the tab width.
+    pub(super) fn apply_tab_indicator(
+        &mut self,
+        mut indicator: impl FnMut(&RunData) -> Option<u16>,
+    ) {
+        for run in &self.data.runs {
+            let mut id = None;
+            let clusters = &self.data.clusters[make_range(run.clusters)];
+            for cluster in clusters {
+                if cluster.info.whitespace() != Whitespace::Tab {
+                    continue;
+                }
+                let id = match *id.get_or_insert_with(|| indicator(run)) {
+                    Some(id) => id,
+                    None => break,
+                };
+                if let Some(g) = cluster
+                    .glyphs_mut(&self.data.detailed_clusters, &mut self.data.glyphs)
+                    .first_mut()
+                {
+                    if g.is_simple() {
+                        g.set_id(id);
+                    } else {
+                        self.data.detailed_glyphs[g.detail_index()].id = id;
+                    }
+                }
+            }
+        }
+    }
+
     pub(super) fn finish(&mut self) {
         // Zero out the advance for the extra trailing space.
-        self.data.glyphs.last_mut().unwrap().clear_advance();
+        let glyph = match self.data.glyphs.last_mut() {
+            Some(glyph) => glyph,
+            // Nothing was shaped, e.g. the font library is empty.
+            None => return,
+        };
+        let advance = if glyph.is_simple() {
+            let advance = glyph.simple_data().1;
+            glyph.clear_advance();
+            advance
+        } else {
+            let detail = &mut self.data.detailed_glyphs[glyph.detail_index()];
+            core::mem::take(&mut detail.advance)
+        };
+        if let Some(run) = self.data.runs.last_mut() {
+            run.advance -= advance;
+        }
     }
 }
 
@@ -508,18 +915,33 @@ impl<'a> Run<'a> {
 
     #[inline]
     pub fn font_id_based_on_attr(&self) -> usize {
-        let is_italic = self.run.span.font_attrs.2 == Style::Italic;
-        let is_bold = self.run.span.font_attrs.1 == Weight::BOLD;
+        font_id_for_attrs(&self.run.span)
+    }
 
-        if is_bold && is_italic {
-            return FONT_ID_BOLD_ITALIC;
-        } else if is_bold {
-            return FONT_ID_BOLD;
-        } else if is_italic {
-            return FONT_ID_ITALIC;
-        }
+    /// Returns the advance of the whitespace at the end of the run, in
+    /// visual order. Only set for runs of broken lines.
+    #[inline]
+    pub fn trailing_whitespace_advance(&self) -> f32 {
+        self.run.trailing_whitespace_advance
+    }
 
-        FONT_ID_REGULAR
+    /// Returns true if the font of the run was picked by fallback, which
+    /// is only tracked with `LayoutContext::set_fallback_trace`.
+    #[inline]
+    pub fn is_fallback(&self) -> bool {
+        self.run.fallback
+    }
+
+    /// Returns the graphic of the run with its placement inside the cell.
+    #[inline]
+    pub fn media(&self) -> Option<SugarGraphic> {
+        self.run.span.media
+    }
+
+    /// Returns the synthetic bold and oblique the renderer should apply.
+    #[inline]
+    pub fn synthesis(&self) -> RunSynthesis {
+        self.run.synthesis
     }
 
     /// Returns the font for the run.
@@ -528,16 +950,40 @@ impl<'a> Run<'a> {
         &self.run.font
     }
 
+    /// Returns the family name of the font for the run, e.g. to log
+    /// which font served a fallback.
+    #[inline]
+    pub fn font_name(&self, library: &FontLibrary) -> Option<String> {
+        library.family_name(self.run.font)
+    }
+
     /// Returns the font size for the run.
     #[inline]
     pub fn font_size(&self) -> f32 {
         self.run.size
     }
 
-    /// Returns the color for the run.
+    /// Returns the source line of the run.
+    #[inline]
+    pub fn line(&self) -> u32 {
+        self.run.line
+    }
+
+    /// Returns the color for the run, fully transparent for concealed
+    /// text.
     #[inline]
     pub fn color(&self) -> [f32; 4] {
-        self.run.span.color
+        if self.run.span.concealed {
+            [0.; 4]
+        } else {
+            self.run.span.color
+        }
+    }
+
+    /// Returns the opacity to multiply the color alpha with.
+    #[inline]
+    pub fn alpha(&self) -> f32 {
+        self.run.span.alpha
     }
 
     /// Returns the bidi level of the run.
@@ -552,6 +998,12 @@ impl<'a> Run<'a> {
         self.run.span.cursor
     }
 
+    /// Returns true if the cursor of the run blinks.
+    #[inline]
+    pub fn cursor_blink(&self) -> bool {
+        self.run.span.cursor_blink
+    }
+
     /// Returns the direction of the run.
     pub fn direction(&self) -> Direction {
         if self.run.level & 1 != 0 {
@@ -575,12 +1027,43 @@ impl<'a> Run<'a> {
         self.run.advance
     }
 
+    /// Returns the advance of the run in cells of `cell_advance`, rounded
+    /// to the nearest integer. A run taking more or less cells than its
+    /// columns points to a font that isn't truly monospace.
+    #[inline]
+    pub fn advance_in_cells(&self, cell_advance: f32) -> f32 {
+        (self.run.advance / cell_advance).round()
+    }
+
     /// Returns true if the run has an background color
     #[inline]
     pub fn background_color(&self) -> Option<[f32; 4]> {
         self.run.span.background_color
     }
 
+    /// Returns the area to fill with the background color as
+    /// `[x, y, width, height]` relative to the paragraph. The rect spans
+    /// the whole line height, leading included, so backgrounds of
+    /// adjacent lines touch. Runs without advance, like combining marks,
+    /// or without a background color return `None`.
+    pub fn background_rect(&self, line: &Line) -> Option<[f32; 4]> {
+        self.background_color()?;
+        let advance = |run: &Run| -> f32 { run.clusters().map(|c| c.advance()).sum() };
+        let width = advance(self);
+        if width <= 0. {
+            return None;
+        }
+
+        let mut x = line.offset();
+        for run in line.runs() {
+            if core::ptr::eq(run.run, self.run) {
+                return Some([x, line.top(), width, line.size()]);
+            }
+            x += advance(&run);
+        }
+        None
+    }
+
     /// Returns true if the run has an underline decoration.
     #[inline]
     pub fn underline(&self) -> bool {
@@ -597,7 +1080,10 @@ impl<'a> Run<'a> {
     /// Returns the underline color for the run.
     #[inline]
     pub fn underline_color(&self) -> [f32; 4] {
-        self.run.span.underline_color.unwrap_or(self.run.span.color)
+        match self.run.span.underline_color {
+            Some(color) if !self.run.span.concealed => color,
+            _ => self.color(),
+        }
     }
 
     /// Returns the underline size for the run.
@@ -609,24 +1095,67 @@ impl<'a> Run<'a> {
             .unwrap_or(self.run.strikeout_size)
     }
 
+    /// Returns the offset of the strikeout line from the baseline.
+    #[inline]
+    pub fn strikeout_offset(&self) -> f32 {
+        self.run.strikeout_offset
+    }
+
+    /// Returns the height of capital letters above the baseline.
+    #[inline]
+    pub fn cap_height(&self) -> f32 {
+        self.run.cap_height
+    }
+
+    /// Returns the thickness of the strikeout line.
+    #[inline]
+    pub fn strikeout_size(&self) -> f32 {
+        self.run.strikeout_size
+    }
+
+    /// Returns the strikethrough decoration of the run.
+    #[inline]
+    pub fn strikethrough(&self) -> Option<StrikethroughInfo> {
+        self.run.span.strikethrough
+    }
+
     /// Returns an iterator over the clusters in logical order.
     #[inline]
     pub fn clusters(&self) -> Clusters<'a> {
         Clusters {
             layout: self.layout,
-            iter: self.layout.clusters[make_range(self.run.clusters)].iter(),
+            iter: make_range(self.run.clusters),
             rev: false,
+            width: self.run.span.width,
+            color: self.color(),
         }
     }
 
+    /// Returns the glyphs of the run in visual order, each with its
+    /// horizontal position starting the run at `origin_x`.
+    pub fn positioned_glyphs(
+        &self,
+        origin_x: f32,
+    ) -> impl Iterator<Item = (Glyph, f32)> + 'a {
+        self.visual_clusters()
+            .flat_map(|cluster| cluster.glyphs())
+            .scan(origin_x, |pen, glyph| {
+                let x = *pen + glyph.x;
+                *pen += glyph.advance;
+                Some((glyph, x))
+            })
+    }
+
     /// Returns an iterator over the clusters in visual order.
     #[inline]
     pub fn visual_clusters(&self) -> Clusters<'a> {
         let rev = self.run.level & 1 != 0;
         Clusters {
             layout: self.layout,
-            iter: self.layout.clusters[make_range(self.run.clusters)].iter(),
+            iter: make_range(self.run.clusters),
             rev,
+            width: self.run.span.width,
+            color: self.color(),
         }
     }
 }
@@ -682,49 +1211,50 @@ impl Glyph {
 pub struct Glyphs<'a> {
     layout: &'a LayoutData,
     iter: core::slice::Iter<'a, GlyphData>,
+    x_offset: f32,
 }
 
-impl<'a> Iterator for Glyphs<'a> {
-    type Item = Glyph;
+impl<'a> Glyphs<'a> {
+    /// Adds `fract_x` to the x offset of every glyph, e.g. the fractional
+    /// part of the pen position for subpixel positioning.
+    #[inline]
+    pub fn with_subpixel_offset(mut self, fract_x: f32) -> Self {
+        self.x_offset = fract_x;
+        self
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let data = self.iter.next()?;
-        if data.is_simple() {
+    #[inline]
+    fn glyph(&self, data: &GlyphData) -> Option<Glyph> {
+        let mut glyph = if data.is_simple() {
             let (id, advance) = data.simple_data();
-            Some(Glyph {
+            Glyph {
                 id,
                 x: 0.,
                 y: 0.,
                 advance,
                 span: id as usize,
-            })
+            }
         } else {
-            self.layout
-                .detailed_glyphs
-                .get(data.detail_index())
-                .copied()
-        }
+            *self.layout.detailed_glyphs.get(data.detail_index())?
+        };
+        glyph.x += self.x_offset;
+        Some(glyph)
+    }
+}
+
+impl<'a> Iterator for Glyphs<'a> {
+    type Item = Glyph;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let data = self.iter.next()?;
+        self.glyph(data)
     }
 }
 
 impl<'a> DoubleEndedIterator for Glyphs<'a> {
     fn next_back(&mut self) -> Option<Self::Item> {
         let data = self.iter.next_back()?;
-        if data.is_simple() {
-            let (id, advance) = data.simple_data();
-            Some(Glyph {
-                id,
-                x: 0.,
-                y: 0.,
-                advance,
-                span: id as usize,
-            })
-        } else {
-            self.layout
-                .detailed_glyphs
-                .get(data.detail_index())
-                .copied()
-        }
+        self.glyph(data)
     }
 }
 
@@ -733,6 +1263,9 @@ impl<'a> DoubleEndedIterator for Glyphs<'a> {
 pub struct Cluster<'a> {
     layout: &'a LayoutData,
     cluster: ClusterData,
+    index: usize,
+    width: f32,
+    color: [f32; 4],
 }
 
 // gar.content.width().unwrap_or(
@@ -779,6 +1312,23 @@ impl<'a> Cluster<'a> {
         self.cluster.is_newline()
     }
 
+    /// Returns true if a word starts or ends right before the cluster.
+    /// Every line break opportunity is a word boundary as well.
+    #[inline]
+    pub fn is_word_boundary(&self) -> bool {
+        self.cluster.info.boundary() != Boundary::None
+    }
+
+    /// Returns true if a line may be broken right before the cluster,
+    /// including mandatory breaks after a newline.
+    #[inline]
+    pub fn is_line_break_opportunity(&self) -> bool {
+        matches!(
+            self.cluster.info.boundary(),
+            Boundary::Line | Boundary::Mandatory
+        )
+    }
+
     /// Returns the byte offset of the cluster in the source text.
     #[inline]
     pub fn offset(&self) -> usize {
@@ -798,6 +1348,37 @@ impl<'a> Cluster<'a> {
         start..start + self.cluster.len as usize
     }
 
+    /// Returns true if every glyph of the cluster is the `.notdef` glyph,
+    /// meaning no loaded font can render it.
+    #[inline]
+    pub fn is_notdef(&self) -> bool {
+        let mut glyphs = self.glyphs().peekable();
+        glyphs.peek().is_some() && glyphs.all(|glyph| glyph.id == 0)
+    }
+
+    /// Returns the color of the cluster, which is the color of its
+    /// fragment unless it's a `.notdef` cluster and a notdef color is set
+    /// on the layout context.
+    #[inline]
+    pub fn color(&self) -> [f32; 4] {
+        match self.layout.notdef_color {
+            Some(color) if self.is_notdef() => color,
+            _ => self.color,
+        }
+    }
+
+    /// Returns the amount of terminal columns taken by the cluster, from
+    /// the `width` of its fragment. Combining marks without a base
+    /// character take no column.
+    #[inline]
+    pub fn columns(&self) -> u8 {
+        if self.cluster.info.is_broken() {
+            0
+        } else {
+            self.width as u8
+        }
+    }
+
     /// Returns an iterator over the glyphs for the cluster.
     #[inline]
     pub fn glyphs(&self) -> Glyphs<'a> {
@@ -807,6 +1388,7 @@ impl<'a> Cluster<'a> {
         Glyphs {
             layout: self.layout,
             iter: glyphs.iter(),
+            x_offset: 0.,
         }
     }
 
@@ -819,42 +1401,104 @@ impl<'a> Cluster<'a> {
             &self.layout.detailed_glyphs,
         )
     }
+
+    /// Returns the advance of each component of a ligature, starting
+    /// with this cluster and followed by its continuations. Other
+    /// clusters, continuations included, yield their own advance.
+    pub fn component_advances(&self) -> impl Iterator<Item = f32> + 'a {
+        let continuations = if self.is_ligature() {
+            &self.layout.clusters[self.index + 1..]
+        } else {
+            &[]
+        };
+        core::iter::once(self.advance()).chain(
+            continuations
+                .iter()
+                .take_while(|cluster| cluster.is_continuation())
+                .map(|cluster| f32::from_bits(cluster.glyphs)),
+        )
+    }
+
+    /// Returns the lowest and highest vertical offsets of the glyphs of
+    /// the cluster relative to the baseline, growing downwards like the
+    /// screen. A negative minimum means marks are stacked above the
+    /// baseline, e.g. to grow a row so stacked diacritics aren't clipped.
+    pub fn mark_extents(&self) -> (f32, f32) {
+        self.glyphs()
+            .fold((0., 0.), |(min, max): (f32, f32), glyph| {
+                (min.min(-glyph.y), max.max(-glyph.y))
+            })
+    }
 }
 
 /// Iterator over the clusters in a run.
 #[derive(Clone)]
 pub struct Clusters<'a> {
     layout: &'a LayoutData,
-    iter: core::slice::Iter<'a, ClusterData>,
+    iter: Range<usize>,
     rev: bool,
+    width: f32,
+    color: [f32; 4],
 }
 
 impl<'a> Iterator for Clusters<'a> {
     type Item = Cluster<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let data = if self.rev {
+        let index = if self.rev {
             self.iter.next_back()?
         } else {
             self.iter.next()?
         };
         Some(Cluster {
             layout: self.layout,
-            cluster: *data,
+            cluster: *self.layout.clusters.get(index)?,
+            index,
+            width: self.width,
+            color: self.color,
         })
     }
 }
 
 impl<'a> DoubleEndedIterator for Clusters<'a> {
     fn next_back(&mut self) -> Option<Self::Item> {
-        let data = self.iter.next_back()?;
+        let index = self.iter.next_back()?;
         Some(Cluster {
             layout: self.layout,
-            cluster: *data,
+            cluster: *self.layout.clusters.get(index)?,
+            index,
+            width: self.width,
+            color: self.color,
         })
     }
 }
 
+/// Result of hit testing a position within a line.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct HitTestResult {
+    /// Byte offset of the cluster in the source text.
+    pub offset: usize,
+    /// True if the position is in the leading half of the cluster, in
+    /// the direction of its run.
+    pub leading: bool,
+    /// Advance of the cluster.
+    pub advance: f32,
+}
+
+/// Vertical metrics of a line, see [`RenderData::line_metrics`].
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LineMetrics {
+    /// Offset of the baseline from the top of the paragraph.
+    pub baseline: f32,
+    pub ascent: f32,
+    pub descent: f32,
+    pub leading: f32,
+    /// Top of the line, half of the leading above the ascent.
+    pub top: f32,
+    /// Bottom of the line and top of the next one.
+    pub bottom: f32,
+}
+
 /// Collection of runs occupying a single line in a paragraph.
 #[derive(Copy, Clone)]
 pub struct Line<'a> {
@@ -884,6 +1528,13 @@ impl<'a> Line<'a> {
         self.line.baseline
     }
 
+    /// Returns true if the line ends in a mandatory break, e.g. a newline
+    /// or a line or paragraph separator, rather than a soft wrap.
+    #[inline]
+    pub fn is_explicit_break(&self) -> bool {
+        self.line.explicit_break
+    }
+
     /// Returns the ascent of the line.
     #[inline]
     pub fn ascent(&self) -> f32 {
@@ -939,6 +1590,19 @@ impl<'a> Line<'a> {
         self.line.ascent + self.line.descent + self.line.leading
     }
 
+    /// Returns the `[x, y]` pen position `advance` pixels into the line,
+    /// relative to the paragraph. Vertical lines advance along y from the
+    /// column at `baseline`.
+    #[inline]
+    pub fn pen_position(&self, advance: f32) -> [f32; 2] {
+        let offset = self.line.x + advance;
+        if self.layout.layout_direction.is_vertical() {
+            [self.line.baseline, offset]
+        } else {
+            [offset, self.line.baseline]
+        }
+    }
+
     /// Returns an iterator over the runs of the line.
     #[inline]
     pub fn runs(&self) -> Runs<'a> {
@@ -949,6 +1613,130 @@ impl<'a> Line<'a> {
         }
     }
 
+    /// Returns the byte offset of the grapheme that follows the one at
+    /// `byte`, or the end of the line after the last grapheme. Ligatures
+    /// are stepped over as a whole.
+    pub fn next_grapheme_offset(&self, byte: usize) -> Option<usize> {
+        if let Some(start) = self.grapheme_starts().find(|start| *start > byte) {
+            return Some(start);
+        }
+        let end = self.text_end();
+        if byte < end {
+            Some(end)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the byte offset of the grapheme that precedes `byte`.
+    /// Ligatures are stepped over as a whole.
+    pub fn prev_grapheme_offset(&self, byte: usize) -> Option<usize> {
+        self.grapheme_starts().filter(|start| *start < byte).last()
+    }
+
+    /// Offsets of the clusters a cursor can stop at, in logical order.
+    fn grapheme_starts(&self) -> impl Iterator<Item = usize> + 'a {
+        self.layout.clusters[make_range(self.line.clusters)]
+            .iter()
+            .filter(|cluster| !cluster.is_continuation())
+            .map(|cluster| cluster.offset as usize)
+    }
+
+    /// Byte offset right after the last cluster of the line.
+    fn text_end(&self) -> usize {
+        self.layout.clusters[make_range(self.line.clusters)]
+            .last()
+            .map(|cluster| cluster.offset as usize + cluster.len as usize)
+            .unwrap_or(0)
+    }
+
+    /// Returns the x position and height of a caret placed before the
+    /// cluster that contains `byte_offset`.
+    ///
+    /// Ligature components are emitted as continuation clusters with
+    /// their share of the ligature advance, so carets can be placed
+    /// between the characters of a ligature.
+    pub fn cursor_geometry(&self, byte_offset: usize) -> Option<(f32, f32)> {
+        let mut pos = self.line.x;
+        for run in self.runs() {
+            let rtl = run.level() & 1 != 0;
+            for cluster in run.visual_clusters() {
+                let advance = cluster.advance();
+                if cluster.range().contains(&byte_offset) {
+                    // Right-to-left clusters start at their right edge.
+                    let x = if rtl { pos + advance } else { pos };
+                    return Some((x, self.size()));
+                }
+                pos += advance;
+            }
+        }
+        None
+    }
+
+    /// Returns the highlight rects, as `[x, y, width, height]`, covering
+    /// the clusters whose source range intersects `range`. Selected
+    /// clusters next to each other in a run share a single rect, so a
+    /// logical range crossing right-to-left text yields several rects.
+    pub fn selection_rects(&self, range: Range<usize>) -> Vec<[f32; 4]> {
+        let mut rects: Vec<[f32; 4]> = Vec::new();
+        let (y, height) = (self.top(), self.size());
+        let mut pos = self.line.x;
+        for run in self.runs() {
+            let mut current: Option<[f32; 4]> = None;
+            for cluster in run.visual_clusters() {
+                let advance = cluster.advance();
+                let cluster_range = cluster.range();
+                if cluster_range.start < range.end && range.start < cluster_range.end {
+                    match &mut current {
+                        Some(rect) if rect[0] + rect[2] == pos => rect[2] += advance,
+                        _ => {
+                            rects.extend(current.take());
+                            current = Some([pos, y, advance, height]);
+                        }
+                    }
+                }
+                pos += advance;
+            }
+            rects.extend(current);
+        }
+        rects
+    }
+
+    /// Returns the top of the line, half of the leading above the ascent.
+    #[inline]
+    fn top(&self) -> f32 {
+        self.line.baseline - self.line.ascent - self.line.leading * 0.5
+    }
+
+    /// Returns the cluster under the horizontal offset `x`, relative to
+    /// the start of the line. Returns `None` when `x` falls outside of
+    /// the line.
+    pub fn hit_test(&self, x: f32) -> Option<HitTestResult> {
+        let x = x - self.line.x;
+        if x < 0. {
+            return None;
+        }
+        let mut pos = 0.;
+        for run in self.runs() {
+            let rtl = run.level() & 1 != 0;
+            // Runs are stored in visual order and right-to-left clusters
+            // are walked backwards, so advances always grow to the right.
+            for cluster in run.visual_clusters() {
+                let advance = cluster.advance();
+                if x < pos + advance {
+                    let left_half = x < pos + advance * 0.5;
+                    return Some(HitTestResult {
+                        offset: cluster.offset(),
+                        leading: left_half != rtl,
+                        advance,
+                    });
+                }
+                pos += advance;
+            }
+        }
+        None
+    }
+
     // pub(super) fn data(&self) -> &'a LineData {
     //     self.line
     // }
@@ -979,3 +1767,1256 @@ impl<'a> Iterator for Lines<'a> {
 pub fn make_range(r: (u32, u32)) -> Range<usize> {
     r.0 as usize..r.1 as usize
 }
+
+/// Font id the renderer draws a span with when it was shaped with the
+/// regular font.
+#[inline]
+pub fn font_id_for_attrs(span: &FragmentStyle) -> usize {
+    let is_italic = span.font_attrs.2 == Style::Italic;
+    let is_bold = span.font_attrs.1 == Weight::BOLD;
+
+    if is_bold && is_italic {
+        return FONT_ID_BOLD_ITALIC;
+    } else if is_bold {
+        return FONT_ID_BOLD;
+    } else if is_italic {
+        return FONT_ID_ITALIC;
+    }
+
+    FONT_ID_REGULAR
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::LayoutContext;
+
+    fn render(fragments: &[(&str, FragmentStyle)]) -> RenderData {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let mut render_data = RenderData::new();
+        let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+        for (text, style) in fragments {
+            lb.add_text(text, *style);
+        }
+        lb.build_into(&mut render_data);
+        render_data
+            .break_lines()
+            .break_without_advance_or_alignment();
+        render_data
+    }
+
+    fn line_advance(render_data: &RenderData) -> f32 {
+        render_data.lines().map(|line| line.advance()).sum()
+    }
+
+    #[test]
+    fn test_font_name_of_regular_run() {
+        let font_library = FontLibrary::default();
+        let render_data = render(&[("abc", FragmentStyle::default())]);
+        let run = render_data
+            .lines()
+            .flat_map(|line| line.runs())
+            .next()
+            .unwrap();
+        assert_eq!(*run.font(), FONT_ID_REGULAR);
+        assert_eq!(
+            run.font_name(&font_library).as_deref(),
+            Some("Cascadia Mono")
+        );
+        assert_eq!(font_library.family_name(usize::MAX), None);
+    }
+
+    #[test]
+    fn test_huge_advances_stay_simple() {
+        let render_data = render(&[(
+            "a",
+            FragmentStyle {
+                font_size: 1200.,
+                ..FragmentStyle::default()
+            },
+        )]);
+        assert!(render_data.data.detailed_glyphs.is_empty());
+        let glyph = render_data.data.glyphs[0];
+        assert!(glyph.is_simple());
+        assert!(glyph.simple_data().1 > 511.);
+
+        let glyph = GlyphData::simple(7, 600., 0);
+        assert_eq!(glyph.simple_data(), (7, 600.));
+    }
+
+    #[test]
+    fn test_subpixel_offset_shifts_every_glyph() {
+        let render_data = render(&[("a\u{301}bc", FragmentStyle::default())]);
+        let clusters: Vec<Cluster> = render_data
+            .lines()
+            .flat_map(|line| line.runs())
+            .flat_map(|run| run.clusters())
+            .collect();
+        for cluster in clusters {
+            let plain: Vec<Glyph> = cluster.glyphs().collect();
+            let shifted: Vec<Glyph> =
+                cluster.glyphs().with_subpixel_offset(0.25).collect();
+            assert_eq!(plain.len(), shifted.len());
+            for (plain, shifted) in plain.iter().zip(&shifted) {
+                assert_eq!(shifted.x, plain.x + 0.25);
+                assert_eq!(shifted.y, plain.y);
+                assert_eq!(shifted.advance, plain.advance);
+            }
+        }
+    }
+
+    #[test]
+    fn test_size_of_three_lines() {
+        assert_eq!(RenderData::new().size(), (0., 0.));
+
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let mut render_data = RenderData::new();
+        let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+        for text in ["ab", "abcde", "a"] {
+            lb.add_text(text, FragmentStyle::default());
+            lb.new_line();
+        }
+        lb.build_into(&mut render_data);
+        render_data
+            .break_lines()
+            .break_without_advance_or_alignment();
+
+        let lines: Vec<Line> = render_data.lines().take(3).collect();
+        let (width, height) = render_data.size();
+        assert_eq!(width, lines[1].advance());
+        let heights: f32 = render_data.lines().map(|line| line.size()).sum();
+        assert_eq!(height, heights);
+    }
+
+    #[test]
+    fn test_fragment_font_size_splits_runs() {
+        let small = FragmentStyle {
+            font_size: 8.,
+            ..FragmentStyle::default()
+        };
+        let render_data = render(&[("ab", FragmentStyle::default()), ("cd", small)]);
+        let sizes: Vec<f32> = render_data.runs().map(|run| run.font_size()).collect();
+        assert_eq!(sizes[..2], [16., 8.]);
+    }
+
+    #[test]
+    fn test_baseline_shift_raises_glyphs_and_line() {
+        let plain = render(&[("x2", FragmentStyle::default())]);
+        let superscript = FragmentStyle {
+            baseline_shift: 4.,
+            ..FragmentStyle::default()
+        };
+        let shifted = render(&[("x", FragmentStyle::default()), ("2", superscript)]);
+
+        let runs: Vec<Run> = shifted.runs().collect();
+        assert!(runs[0]
+            .clusters()
+            .flat_map(|c| c.glyphs())
+            .all(|g| g.y == 0.));
+        assert!(runs[1]
+            .clusters()
+            .flat_map(|c| c.glyphs())
+            .all(|g| g.y == 4.));
+        let ascent =
+            |render_data: &RenderData| render_data.lines().next().unwrap().ascent();
+        assert_eq!(ascent(&shifted), ascent(&plain) + 4.);
+    }
+
+    #[test]
+    fn test_cap_height_is_below_ascent() {
+        let render_data = render(&[("Hx", FragmentStyle::default())]);
+        let line = render_data.lines().next().unwrap();
+        let cap_height = render_data.cap_height(0).unwrap();
+        assert!(cap_height > 0.);
+        assert!(cap_height < line.ascent());
+        assert_eq!(render_data.cap_height(1), None);
+    }
+
+    #[test]
+    fn test_reversed_run_swaps_colors() {
+        let foreground = [0.9, 0.8, 0.7, 1.];
+        let surface = [0.1, 0.2, 0.3, 1.];
+        let mut style = FragmentStyle {
+            color: foreground,
+            reversed: true,
+            ..FragmentStyle::default()
+        };
+        style.apply_reverse(surface);
+
+        let render_data = render(&[("abc", style)]);
+        let run = render_data
+            .lines()
+            .flat_map(|line| line.runs())
+            .next()
+            .unwrap();
+        assert_eq!(run.color(), surface);
+        assert_eq!(run.background_color(), Some(foreground));
+
+        // The fragment background wins over the surface one.
+        let background = [0.4, 0.4, 0.4, 1.];
+        let mut style = FragmentStyle {
+            color: foreground,
+            background_color: Some(background),
+            reversed: true,
+            ..FragmentStyle::default()
+        };
+        style.apply_reverse(surface);
+        assert_eq!(style.color, background);
+        assert_eq!(style.background_color, Some(foreground));
+    }
+
+    #[test]
+    fn test_concealed_run_keeps_advances() {
+        let visible = render(&[("abc", FragmentStyle::default())]);
+        let concealed = render(&[(
+            "abc",
+            FragmentStyle {
+                concealed: true,
+                underline_color: Some([1., 0., 0., 1.]),
+                ..FragmentStyle::default()
+            },
+        )]);
+        assert_eq!(line_advance(&concealed), line_advance(&visible));
+
+        let run = concealed
+            .lines()
+            .flat_map(|line| line.runs())
+            .next()
+            .unwrap();
+        assert_eq!(run.color(), [0.; 4]);
+        assert_eq!(run.underline_color(), [0.; 4]);
+        assert!(run.clusters().all(|cluster| cluster.color() == [0.; 4]));
+        let advances: Vec<f32> =
+            run.clusters().map(|cluster| cluster.advance()).collect();
+        let visible_advances: Vec<f32> = visible
+            .lines()
+            .flat_map(|line| line.runs())
+            .flat_map(|run| run.clusters())
+            .map(|cluster| cluster.advance())
+            .collect();
+        assert_eq!(advances, visible_advances);
+    }
+
+    #[test]
+    fn test_run_and_line_for_cluster() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let mut render_data = RenderData::new();
+        let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+        lb.add_text("ab", FragmentStyle::default());
+        lb.new_line();
+        lb.add_text("cd", FragmentStyle::default());
+        lb.add_text(
+            "ef",
+            FragmentStyle {
+                color: [1., 0., 0., 1.],
+                ..FragmentStyle::default()
+            },
+        );
+        lb.build_into(&mut render_data);
+        render_data
+            .break_lines()
+            .break_without_advance_or_alignment();
+
+        // Clusters are "ab", "cd", "ef" and the trailing space.
+        let run = render_data.run_for_cluster(4).unwrap();
+        assert_eq!(run.color(), [1., 0., 0., 1.]);
+        let run = render_data.run_for_cluster(2).unwrap();
+        assert_eq!(run.color(), FragmentStyle::default().color);
+        let line = render_data.line_for_cluster(4).unwrap();
+        assert_eq!(
+            line.baseline(),
+            render_data.lines().nth(1).unwrap().baseline()
+        );
+        let line = render_data.line_for_cluster(1).unwrap();
+        assert_eq!(
+            line.baseline(),
+            render_data.lines().next().unwrap().baseline()
+        );
+
+        let clusters = render_data.data.clusters.len() as u32;
+        assert!(render_data.run_for_cluster(clusters).is_none());
+        assert!(render_data.line_for_cluster(clusters).is_none());
+    }
+
+    #[test]
+    fn test_runs_cover_every_line() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let mut render_data = RenderData::new();
+        let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+        lb.add_text("ab", FragmentStyle::default());
+        lb.add_text(
+            "cd",
+            FragmentStyle {
+                color: [1., 0., 0., 1.],
+                ..FragmentStyle::default()
+            },
+        );
+        lb.new_line();
+        lb.add_text("ef", FragmentStyle::default());
+        lb.build_into(&mut render_data);
+        render_data
+            .break_lines()
+            .break_without_advance_or_alignment();
+
+        let per_line: usize = render_data.lines().map(|line| line.runs().count()).sum();
+        assert_eq!(render_data.runs().count(), per_line);
+        let lines: Vec<u32> = render_data.runs().map(|run| run.line()).collect();
+        assert_eq!(lines, vec![0, 0, 1]);
+    }
+
+    #[test]
+    fn test_force_zero_advance_collapses_run() {
+        let render_data = render(&[
+            (
+                "a\u{301}b",
+                FragmentStyle {
+                    force_zero_advance: true,
+                    ..FragmentStyle::default()
+                },
+            ),
+            ("c", FragmentStyle::default()),
+        ]);
+        let runs: Vec<Run> = render_data.runs().collect();
+        assert_eq!(runs[0].advance(), 0.);
+        assert!(runs[0].clusters().all(|cluster| cluster.advance() == 0.));
+        assert!(runs[0]
+            .clusters()
+            .flat_map(|cluster| cluster.glyphs())
+            .all(|glyph| glyph.advance == 0.));
+        assert!(runs[1].advance() > 0.);
+    }
+
+    #[test]
+    fn test_letter_spacing_widens_clusters() {
+        let plain = render(&[("abc", FragmentStyle::default())]);
+        let spaced = render(&[(
+            "abc",
+            FragmentStyle {
+                letter_spacing: 2.,
+                ..FragmentStyle::default()
+            },
+        )]);
+
+        let plain_clusters: Vec<f32> = plain
+            .lines()
+            .flat_map(|line| line.runs())
+            .flat_map(|run| run.clusters())
+            .map(|cluster| cluster.advance())
+            .collect();
+        let spaced_clusters: Vec<f32> = spaced
+            .lines()
+            .flat_map(|line| line.runs())
+            .flat_map(|run| run.clusters())
+            .map(|cluster| cluster.advance())
+            .collect();
+        for i in 0..3 {
+            assert_eq!(spaced_clusters[i], plain_clusters[i] + 2.);
+        }
+        assert_eq!(line_advance(&spaced), line_advance(&plain) + 6.);
+    }
+
+    #[test]
+    fn test_word_spacing_only_widens_spaces() {
+        let plain = render(&[("a b c", FragmentStyle::default())]);
+        let spaced = render(&[(
+            "a b c",
+            FragmentStyle {
+                word_spacing: 4.,
+                ..FragmentStyle::default()
+            },
+        )]);
+
+        // Two inner spaces receive word spacing, letters are untouched.
+        assert_eq!(line_advance(&spaced), line_advance(&plain) + 8.);
+        let letters = |render_data: &RenderData| -> Vec<f32> {
+            render_data
+                .lines()
+                .flat_map(|line| line.runs())
+                .flat_map(|run| run.clusters())
+                .filter(|cluster| !cluster.info().is_whitespace())
+                .map(|cluster| cluster.advance())
+                .collect()
+        };
+        assert_eq!(letters(&spaced), letters(&plain));
+    }
+
+    #[test]
+    fn test_line_spacing_scales_line_size() {
+        let plain = render(&[("abc", FragmentStyle::default())]);
+        let spaced = render(&[(
+            "abc",
+            FragmentStyle {
+                line_spacing: 1.5,
+                ..FragmentStyle::default()
+            },
+        )]);
+
+        let plain_size = plain.lines().next().unwrap().size();
+        let spaced_size = spaced.lines().next().unwrap().size();
+        // Line metrics are rounded to whole pixels.
+        assert!((spaced_size - plain_size * 1.5).abs() <= 2.);
+    }
+
+    #[test]
+    fn test_line_spacing_uses_largest_fragment() {
+        let spacing = FragmentStyle {
+            line_spacing: 1.5,
+            ..FragmentStyle::default()
+        };
+        let mixed = render(&[("ab", FragmentStyle::default()), ("cd", spacing)]);
+        let spaced = render(&[("abcd", spacing)]);
+
+        assert_eq!(
+            mixed.lines().next().unwrap().size(),
+            spaced.lines().next().unwrap().size()
+        );
+    }
+
+    fn synthetic(levels: &[u8], clusters_per_run: u32, advance: f32) -> RenderData {
+        let mut render_data = RenderData::new();
+        let mut offset = 0;
+        for level in levels {
+            let start = render_data.data.clusters.len() as u32;
+            for _ in 0..clusters_per_run {
+                render_data.data.clusters.push(ClusterData {
+                    info: Default::default(),
+                    flags: 0,
+                    len: 1,
+                    offset,
+                    glyphs: render_data.data.glyphs.len() as u32,
+                });
+                render_data
+                    .data
+                    .glyphs
+                    .push(GlyphData::simple(1, advance, 0));
+                offset += 1;
+            }
+            render_data.data.runs.push(RunData {
+                level: *level,
+                clusters: (start, start + clusters_per_run),
+                ..RunData::default()
+            });
+        }
+        render_data
+            .break_lines()
+            .break_without_advance_or_alignment();
+        render_data
+    }
+
+    #[test]
+    fn test_hit_test_cluster_boundaries() {
+        let render_data = synthetic(&[0], 3, 10.);
+        let line = render_data.lines().next().unwrap();
+
+        let hit = line.hit_test(0.).unwrap();
+        assert_eq!((hit.offset, hit.leading, hit.advance), (0, true, 10.));
+        let hit = line.hit_test(9.9).unwrap();
+        assert_eq!((hit.offset, hit.leading), (0, false));
+        let hit = line.hit_test(10.).unwrap();
+        assert_eq!((hit.offset, hit.leading), (1, true));
+        let hit = line.hit_test(29.).unwrap();
+        assert_eq!((hit.offset, hit.leading), (2, false));
+    }
+
+    #[test]
+    fn test_hit_test_past_the_end() {
+        let render_data = synthetic(&[0], 3, 10.);
+        let line = render_data.lines().next().unwrap();
+
+        assert_eq!(line.hit_test(30.), None);
+        assert_eq!(line.hit_test(-1.), None);
+    }
+
+    #[test]
+    fn test_hit_test_rtl_run() {
+        let render_data = synthetic(&[1], 3, 10.);
+        let line = render_data.lines().next().unwrap();
+
+        // The first logical cluster is drawn on the right edge.
+        let hit = line.hit_test(25.).unwrap();
+        assert_eq!((hit.offset, hit.leading), (0, true));
+        let hit = line.hit_test(21.).unwrap();
+        assert_eq!((hit.offset, hit.leading), (0, false));
+        let hit = line.hit_test(1.).unwrap();
+        assert_eq!((hit.offset, hit.leading), (2, false));
+    }
+
+    #[test]
+    fn test_cursor_geometry_inside_ligature() {
+        // "fix" where "fi" is a single ligature glyph of 20 units.
+        let mut render_data = RenderData::new();
+        render_data.data.glyphs.push(GlyphData::simple(1, 20., 0));
+        render_data.data.glyphs.push(GlyphData::simple(2, 10., 0));
+        render_data
+            .data
+            .detailed_clusters
+            .push(DetailedClusterData {
+                glyphs: (0, 1),
+                advance: 10.,
+            });
+        render_data.data.clusters.push(ClusterData {
+            info: Default::default(),
+            flags: CLUSTER_LIGATURE | CLUSTER_DETAILED,
+            len: 1,
+            offset: 0,
+            glyphs: 0,
+        });
+        render_data.data.clusters.push(ClusterData {
+            info: Default::default(),
+            flags: CLUSTER_CONTINUATION | CLUSTER_EMPTY | CLUSTER_LAST_CONTINUATION,
+            len: 1,
+            offset: 1,
+            glyphs: 10f32.to_bits(),
+        });
+        render_data.data.clusters.push(ClusterData {
+            info: Default::default(),
+            flags: 0,
+            len: 1,
+            offset: 2,
+            glyphs: 1,
+        });
+        render_data.data.runs.push(RunData {
+            clusters: (0, 3),
+            ascent: 8.,
+            descent: 2.,
+            ..RunData::default()
+        });
+        render_data
+            .break_lines()
+            .break_without_advance_or_alignment();
+        let line = render_data.lines().next().unwrap();
+
+        assert_eq!(line.cursor_geometry(0), Some((0., 10.)));
+        assert_eq!(line.cursor_geometry(1), Some((10., 10.)));
+        assert_eq!(line.cursor_geometry(2), Some((20., 10.)));
+        assert_eq!(line.cursor_geometry(3), None);
+    }
+
+    #[test]
+    fn test_runs_expose_strikeout_metrics() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let mut metrics = vec![];
+        // The second build reuses the cached line.
+        for _ in 0..2 {
+            let mut render_data = RenderData::new();
+            let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+            lb.set_hash(1);
+            lb.add_text("abc", FragmentStyle::default());
+            lb.build_into(&mut render_data);
+            render_data
+                .break_lines()
+                .break_without_advance_or_alignment();
+            let run = render_data.lines().next().unwrap().runs().next().unwrap();
+            metrics.push((run.strikeout_offset(), run.strikeout_size()));
+        }
+
+        assert!(metrics[0].0 > 0.);
+        assert!(metrics[0].1 > 0.);
+        assert_eq!(metrics[0], metrics[1]);
+    }
+
+    #[test]
+    fn test_underline_and_strikethrough_on_same_fragment() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let style = FragmentStyle {
+            underline: true,
+            strikethrough: Some(StrikethroughInfo {
+                size: 1.,
+                is_doubled: true,
+            }),
+            ..FragmentStyle::default()
+        };
+        // The second build reuses the cached line.
+        for _ in 0..2 {
+            let mut render_data = RenderData::new();
+            let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+            lb.set_hash(1);
+            lb.add_text("abc", style);
+            lb.build_into(&mut render_data);
+            render_data
+                .break_lines()
+                .break_without_advance_or_alignment();
+            let run = render_data.lines().next().unwrap().runs().next().unwrap();
+            assert!(run.underline());
+            assert_eq!(run.strikethrough(), style.strikethrough);
+        }
+    }
+
+    #[test]
+    fn test_cursor_blink_survives_run_cache() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let style = FragmentStyle {
+            cursor: SugarCursor::Block([1., 1., 1., 1.]),
+            cursor_blink: true,
+            ..FragmentStyle::default()
+        };
+        // The second build reuses the cached line.
+        for _ in 0..2 {
+            let mut render_data = RenderData::new();
+            let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+            lb.set_hash(1);
+            lb.add_text("a", style);
+            lb.build_into(&mut render_data);
+            render_data
+                .break_lines()
+                .break_without_advance_or_alignment();
+            let run = render_data.lines().next().unwrap().runs().next().unwrap();
+            assert_eq!(run.cursor(), style.cursor);
+            assert!(run.cursor_blink());
+        }
+    }
+
+    #[test]
+    fn test_media_placement_survives_run_cache() {
+        use crate::components::core::shapes::Rectangle;
+        use crate::SugarGraphicId;
+
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let media = SugarGraphic {
+            id: SugarGraphicId(1),
+            width: 20,
+            height: 10,
+            offset: (2.5, 4.),
+            crop: Some(Rectangle {
+                x: 1,
+                y: 2,
+                width: 8,
+                height: 6,
+            }),
+        };
+        let style = FragmentStyle {
+            media: Some(media),
+            ..FragmentStyle::default()
+        };
+        // The second build reuses the cached line.
+        for _ in 0..2 {
+            let mut render_data = RenderData::new();
+            let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+            lb.set_hash(1);
+            lb.add_text(" ", style);
+            lb.build_into(&mut render_data);
+            render_data
+                .break_lines()
+                .break_without_advance_or_alignment();
+            let run = render_data.lines().next().unwrap().runs().next().unwrap();
+            assert_eq!(run.media(), Some(media));
+        }
+    }
+
+    #[test]
+    fn test_cached_ligature_keeps_every_glyph() {
+        let mut entry = RunCacheEntry::default();
+        entry.runs.push(CachedRunData {
+            clusters: vec![CachedClusterData {
+                info: Default::default(),
+                flags: CLUSTER_LIGATURE | CLUSTER_DETAILED,
+                len: 1,
+                offset: 0,
+                glyphs: SmallVec::from_slice(&[
+                    GlyphData::simple(1, 10., 0),
+                    GlyphData::simple(2, 5., 0),
+                ]),
+                details: SmallVec::from_slice(&[DetailedClusterData {
+                    glyphs: (0, 2),
+                    advance: 15.,
+                }]),
+                detailed_glyphs: Vec::new(),
+            }],
+            coords: Vec::new(),
+            span: FragmentStyle::default(),
+            line: 0,
+            font: 0,
+            size: 16.,
+            level: 0,
+            whitespace: false,
+            trailing_whitespace: false,
+            ascent: 0.,
+            descent: 0.,
+            leading: 0.,
+            strikeout_offset: 0.,
+            strikeout_size: 0.,
+            cap_height: 0.,
+            advance: 15.,
+            synthesis: RunSynthesis::default(),
+            fallback: false,
+        });
+
+        let mut render_data = RenderData::new();
+        // Cached glyphs are appended after any glyph already laid out.
+        render_data.data.glyphs.push(GlyphData::simple(3, 1., 0));
+        assert!(render_data.push_run_from_cached_line(&entry, 0));
+        render_data
+            .break_lines()
+            .break_without_advance_or_alignment();
+
+        let line = render_data.lines().next().unwrap();
+        let cluster = line.runs().next().unwrap().clusters().next().unwrap();
+        let glyphs: Vec<(u16, f32)> =
+            cluster.glyphs().map(|g| (g.id, g.advance)).collect();
+        assert_eq!(glyphs, vec![(1, 10.), (2, 5.)]);
+        assert_eq!(cluster.advance(), 15.);
+    }
+
+    #[test]
+    fn test_inconsistent_cached_line_is_rejected() {
+        let cluster = CachedClusterData {
+            info: Default::default(),
+            flags: 0,
+            len: 1,
+            offset: 0,
+            glyphs: SmallVec::new(),
+            details: SmallVec::new(),
+            detailed_glyphs: Vec::new(),
+        };
+        let mut entry = RunCacheEntry::default();
+        entry.runs.push(CachedRunData {
+            clusters: vec![cluster.clone()],
+            coords: Vec::new(),
+            span: FragmentStyle::default(),
+            line: 0,
+            font: 0,
+            size: 16.,
+            level: 0,
+            whitespace: false,
+            trailing_whitespace: false,
+            ascent: 0.,
+            descent: 0.,
+            leading: 0.,
+            strikeout_offset: 0.,
+            strikeout_size: 0.,
+            cap_height: 0.,
+            advance: 0.,
+            synthesis: RunSynthesis::default(),
+            fallback: false,
+        });
+
+        // A simple cluster without its glyph.
+        let mut render_data = RenderData::new();
+        assert!(!entry.is_valid());
+        assert!(!render_data.push_run_from_cached_line(&entry, 0));
+        assert!(render_data.data.runs.is_empty());
+        assert!(render_data.data.clusters.is_empty());
+
+        // A detailed glyph whose detail is missing.
+        entry.runs[0].clusters[0].glyphs.push(GlyphData {
+            data: GLYPH_DETAILED,
+            size: 0,
+        });
+        assert!(!entry.is_valid());
+        // A detailed cluster without its detail.
+        entry.runs[0].clusters[0] = CachedClusterData {
+            flags: CLUSTER_DETAILED,
+            ..cluster
+        };
+        assert!(!entry.is_valid());
+        render_data
+            .break_lines()
+            .break_without_advance_or_alignment();
+        assert_eq!(render_data.lines().count(), 0);
+    }
+
+    #[test]
+    fn test_restore_snapshot_after_mutation() {
+        fn signature(render_data: &RenderData) -> Vec<(usize, Vec<u16>, f32)> {
+            render_data
+                .lines()
+                .flat_map(|line| line.runs())
+                .flat_map(|run| run.clusters())
+                .map(|cluster| {
+                    let glyphs = cluster.glyphs().map(|glyph| glyph.id).collect();
+                    (cluster.range().start, glyphs, cluster.advance())
+                })
+                .collect()
+        }
+
+        let mut render_data = render(&[("abc\u{301} def", FragmentStyle::default())]);
+        let before = signature(&render_data);
+        let snapshot = render_data.snapshot();
+
+        render_data.clear();
+        assert!(signature(&render_data).is_empty());
+        render_data.restore(snapshot);
+        assert_eq!(signature(&render_data), before);
+    }
+
+    #[test]
+    fn test_clear_empties_layout_and_lines() {
+        let mut render_data = render(&[("abc\ndef", FragmentStyle::default())]);
+        assert!(!render_data.is_empty());
+        render_data.clear();
+
+        assert!(render_data.is_empty());
+        assert!(render_data.data.glyphs.is_empty());
+        assert!(render_data.data.detailed_glyphs.is_empty());
+        assert!(render_data.data.clusters.is_empty());
+        assert!(render_data.data.detailed_clusters.is_empty());
+        assert!(render_data.data.runs.is_empty());
+        assert!(render_data.data.coords.is_empty());
+        assert!(render_data.line_data.runs.is_empty());
+        assert!(render_data.line_data.clusters.is_empty());
+        assert!(render_data.line_data.visual_clusters.is_empty());
+    }
+
+    #[test]
+    fn test_clear_drops_graphics_and_cached_line() {
+        use crate::SugarGraphicId;
+
+        let media = SugarGraphic {
+            id: SugarGraphicId(1),
+            width: 10,
+            height: 10,
+            offset: (0., 0.),
+            crop: None,
+        };
+        let mut render_data = render(&[
+            ("a", FragmentStyle::default()),
+            (
+                " ",
+                FragmentStyle {
+                    media: Some(media),
+                    ..FragmentStyle::default()
+                },
+            ),
+        ]);
+        assert!(render_data
+            .lines()
+            .any(|line| line.runs().any(|run| run.media().is_some())));
+        assert!(!render_data.last_cached_run.runs.is_empty());
+
+        render_data.clear();
+        assert!(render_data.last_cached_run.runs.is_empty());
+        assert_eq!(render_data.last_line, 0);
+        assert_eq!(render_data.data.last_span, 0);
+        assert!(render_data
+            .lines()
+            .all(|line| line.runs().all(|run| run.media().is_none())));
+    }
+
+    #[test]
+    fn test_grapheme_navigation_skips_emoji_and_ligatures() {
+        // Family emoji (25 bytes) collapsed into one cluster, an "ffi"
+        // ligature and a trailing "x".
+        let mut render_data = RenderData::new();
+        let clusters = [
+            (0, 25, 0),
+            (25, 1, CLUSTER_LIGATURE),
+            (26, 1, CLUSTER_CONTINUATION | CLUSTER_EMPTY),
+            (
+                27,
+                1,
+                CLUSTER_CONTINUATION | CLUSTER_EMPTY | CLUSTER_LAST_CONTINUATION,
+            ),
+            (28, 1, 0),
+        ];
+        for (offset, len, flags) in clusters {
+            let glyphs = if flags & CLUSTER_CONTINUATION != 0 {
+                10f32.to_bits()
+            } else {
+                render_data.data.glyphs.push(GlyphData::simple(1, 10., 0));
+                render_data.data.glyphs.len() as u32 - 1
+            };
+            render_data.data.clusters.push(ClusterData {
+                info: Default::default(),
+                flags,
+                len,
+                offset,
+                glyphs,
+            });
+        }
+        render_data.data.runs.push(RunData {
+            clusters: (0, 5),
+            ..RunData::default()
+        });
+        render_data
+            .break_lines()
+            .break_without_advance_or_alignment();
+        let line = render_data.lines().next().unwrap();
+
+        assert_eq!(line.next_grapheme_offset(0), Some(25));
+        assert_eq!(line.next_grapheme_offset(25), Some(28));
+        assert_eq!(line.next_grapheme_offset(26), Some(28));
+        assert_eq!(line.next_grapheme_offset(28), Some(29));
+        assert_eq!(line.next_grapheme_offset(29), None);
+
+        assert_eq!(line.prev_grapheme_offset(29), Some(28));
+        assert_eq!(line.prev_grapheme_offset(28), Some(25));
+        assert_eq!(line.prev_grapheme_offset(27), Some(25));
+        assert_eq!(line.prev_grapheme_offset(25), Some(0));
+        assert_eq!(line.prev_grapheme_offset(0), None);
+    }
+
+    #[test]
+    fn test_background_rect_fills_line_height() {
+        let background = FragmentStyle {
+            background_color: Some([1., 0., 0., 1.]),
+            ..FragmentStyle::default()
+        };
+        let render_data = render(&[("ab", FragmentStyle::default()), ("cd", background)]);
+        let line = render_data.lines().next().unwrap();
+        let runs: Vec<Run> = line.runs().collect();
+
+        assert_eq!(runs[0].background_rect(&line), None);
+        let [x, y, width, height] = runs[1].background_rect(&line).unwrap();
+        assert_eq!(x, runs[0].clusters().map(|c| c.advance()).sum::<f32>());
+        assert_eq!(width, runs[1].clusters().map(|c| c.advance()).sum::<f32>());
+        assert_eq!(height, line.size());
+        assert_eq!(
+            y + height,
+            line.baseline() + line.descent() + line.leading() * 0.5
+        );
+    }
+
+    #[test]
+    fn test_background_rect_skips_zero_advance_runs() {
+        let mut render_data = synthetic(&[0, 0], 2, 0.);
+        render_data.line_data.runs[1].span.background_color = Some([1., 0., 0., 1.]);
+        let line = render_data.lines().next().unwrap();
+        let run = line.runs().nth(1).unwrap();
+        assert_eq!(run.background_rect(&line), None);
+    }
+
+    #[test]
+    fn test_selection_rects_split_across_runs() {
+        let render_data = synthetic(&[0, 0], 2, 10.);
+        let line = render_data.lines().next().unwrap();
+
+        let rects = line.selection_rects(1..4);
+        let spans: Vec<(f32, f32)> = rects.iter().map(|r| (r[0], r[2])).collect();
+        assert_eq!(spans, vec![(10., 10.), (20., 20.)]);
+        assert!(rects.iter().all(|r| r[3] == line.size()));
+        assert!(line.selection_rects(4..4).is_empty());
+    }
+
+    #[test]
+    fn test_selection_rects_rtl_segment() {
+        // Offsets 2 and 3 are right-to-left, drawn as 3 then 2.
+        let render_data = synthetic(&[0, 1, 0], 2, 10.);
+        let line = render_data.lines().next().unwrap();
+
+        let spans: Vec<(f32, f32)> = line
+            .selection_rects(1..3)
+            .iter()
+            .map(|r| (r[0], r[2]))
+            .collect();
+        assert_eq!(spans, vec![(10., 10.), (30., 10.)]);
+
+        let spans: Vec<(f32, f32)> = line
+            .selection_rects(1..5)
+            .iter()
+            .map(|r| (r[0], r[2]))
+            .collect();
+        assert_eq!(spans, vec![(10., 10.), (20., 20.), (40., 10.)]);
+    }
+
+    #[test]
+    fn test_component_advances_of_three_part_ligature() {
+        // "ffi" shaped as a single glyph of 30 units followed by "x".
+        let mut render_data = RenderData::new();
+        render_data.data.glyphs.push(GlyphData::simple(1, 30., 0));
+        render_data.data.glyphs.push(GlyphData::simple(2, 10., 0));
+        render_data
+            .data
+            .detailed_clusters
+            .push(DetailedClusterData {
+                glyphs: (0, 1),
+                advance: 10.,
+            });
+        render_data.data.clusters.push(ClusterData {
+            info: Default::default(),
+            flags: CLUSTER_LIGATURE | CLUSTER_DETAILED,
+            len: 1,
+            offset: 0,
+            glyphs: 0,
+        });
+        for offset in 1..3 {
+            render_data.data.clusters.push(ClusterData {
+                info: Default::default(),
+                flags: CLUSTER_CONTINUATION | CLUSTER_EMPTY,
+                len: 1,
+                offset,
+                glyphs: 10f32.to_bits(),
+            });
+        }
+        render_data.data.clusters[2].flags |= CLUSTER_LAST_CONTINUATION;
+        render_data.data.clusters.push(ClusterData {
+            info: Default::default(),
+            flags: 0,
+            len: 1,
+            offset: 3,
+            glyphs: 1,
+        });
+        render_data.data.runs.push(RunData {
+            clusters: (0, 4),
+            ..RunData::default()
+        });
+        render_data
+            .break_lines()
+            .break_without_advance_or_alignment();
+        let line = render_data.lines().next().unwrap();
+        let run = line.runs().next().unwrap();
+        let clusters: Vec<Cluster> = run.clusters().collect();
+
+        let ligature: Vec<f32> = clusters[0].component_advances().collect();
+        assert_eq!(ligature, vec![10., 10., 10.]);
+        let glyph_advance: f32 = clusters[0].glyphs().map(|g| g.advance).sum();
+        assert_eq!(ligature.iter().sum::<f32>(), glyph_advance);
+        let continuation: Vec<f32> = clusters[1].component_advances().collect();
+        assert_eq!(continuation, vec![10.]);
+        let plain: Vec<f32> = clusters[3].component_advances().collect();
+        assert_eq!(plain, vec![10.]);
+    }
+
+    #[test]
+    fn test_mark_extents_of_stacked_marks() {
+        // A base glyph followed by two combining marks stacked above it.
+        let mut render_data = RenderData::new();
+        for (id, y, advance) in [(1, 0., 10.), (2, 6., 0.), (3, 12., 0.)] {
+            render_data.data.glyphs.push(GlyphData {
+                data: GLYPH_DETAILED | render_data.data.detailed_glyphs.len() as u64,
+                size: 0,
+            });
+            render_data.data.detailed_glyphs.push(Glyph {
+                id,
+                x: 0.,
+                y,
+                advance,
+                span: 0,
+            });
+        }
+        render_data.data.glyphs.push(GlyphData::simple(4, 10., 0));
+        render_data
+            .data
+            .detailed_clusters
+            .push(DetailedClusterData {
+                glyphs: (0, 3),
+                advance: 10.,
+            });
+        render_data.data.clusters.push(ClusterData {
+            info: Default::default(),
+            flags: CLUSTER_DETAILED,
+            len: 5,
+            offset: 0,
+            glyphs: 0,
+        });
+        render_data.data.clusters.push(ClusterData {
+            info: Default::default(),
+            flags: 0,
+            len: 1,
+            offset: 5,
+            glyphs: 3,
+        });
+        render_data.data.runs.push(RunData {
+            clusters: (0, 2),
+            ..RunData::default()
+        });
+        render_data
+            .break_lines()
+            .break_without_advance_or_alignment();
+
+        let run = render_data.lines().next().unwrap().runs().next().unwrap();
+        let extents: Vec<(f32, f32)> = run
+            .clusters()
+            .map(|cluster| cluster.mark_extents())
+            .collect();
+        assert_eq!(extents, vec![(-12., 0.), (0., 0.)]);
+    }
+
+    #[test]
+    fn test_advance_in_cells_of_ascii_run() {
+        let render_data = render(&[("hello", FragmentStyle::default())]);
+        let run = render_data.lines().next().unwrap().runs().next().unwrap();
+        let cell_advance = run.clusters().next().unwrap().advance();
+        // The trailing space added by the builder takes no cell.
+        assert_eq!(run.advance_in_cells(cell_advance), 5.);
+        assert_eq!(run.advance(), cell_advance * 5.);
+    }
+
+    #[test]
+    fn test_cluster_break_opportunities() {
+        let text = "ab cd\nef";
+        let render_data = render(&[(text, FragmentStyle::default())]);
+        let clusters: Vec<(&str, bool, bool)> = render_data
+            .lines()
+            .flat_map(|line| line.runs())
+            .flat_map(|run| run.clusters())
+            .filter(|cluster| cluster.range().end <= text.len())
+            .map(|cluster| {
+                (
+                    &text[cluster.range()],
+                    cluster.is_word_boundary(),
+                    cluster.is_line_break_opportunity(),
+                )
+            })
+            .collect();
+
+        let flags = |s: &str| clusters.iter().find(|c| c.0 == s).map(|c| (c.1, c.2));
+        assert_eq!(flags("b"), Some((false, false)));
+        // A word ends at the space, the line may only break after it.
+        assert_eq!(flags(" "), Some((true, false)));
+        assert_eq!(flags("c"), Some((true, true)));
+        // Mandatory break after the newline.
+        assert_eq!(flags("e"), Some((true, true)));
+    }
+
+    #[test]
+    fn test_word_range_at_punctuation() {
+        let render_data = render(&[("hello-world", FragmentStyle::default())]);
+        assert_eq!(render_data.word_range_at(2), 0..5);
+        assert_eq!(render_data.word_range_at(4), 0..5);
+        // The hyphen attaches to neither word.
+        assert_eq!(render_data.word_range_at(5), 5..6);
+        assert_eq!(render_data.word_range_at(8), 6..11);
+        assert_eq!(render_data.word_range_at(100), 100..100);
+    }
+
+    #[test]
+    fn test_word_range_at_ideographs() {
+        // Three ideographs of three bytes each.
+        let render_data = render(&[("日本語", FragmentStyle::default())]);
+        assert_eq!(render_data.word_range_at(0), 0..3);
+        assert_eq!(render_data.word_range_at(4), 3..6);
+        assert_eq!(render_data.word_range_at(8), 6..9);
+    }
+
+    #[test]
+    fn test_cluster_columns_follow_fragment_width() {
+        use crate::{Sugar, SugarDecoration, SugarStyle};
+
+        let sugar = |content| Sugar {
+            content,
+            repeated: 0,
+            foreground_color: [1., 1., 1., 1.],
+            background_color: None,
+            style: SugarStyle::Disabled,
+            decoration: SugarDecoration::Disabled,
+            cursor: SugarCursor::Disabled,
+            media: None,
+        };
+        let columns = |content: char| {
+            let text = content.to_string();
+            let render_data = render(&[(&text, FragmentStyle::from(&sugar(content)))]);
+            let line = render_data.lines().next().unwrap();
+            let run = line.runs().next().unwrap();
+            let cluster = run.clusters().next().unwrap();
+            cluster.columns()
+        };
+
+        assert_eq!(columns('あ'), 2);
+        assert_eq!(columns('a'), 1);
+        assert_eq!(columns('\u{301}'), 0);
+    }
+
+    #[test]
+    fn test_notdef_clusters_are_reported_missing() {
+        // Last private use codepoint, not covered by any bundled font.
+        let render_data =
+            render(&[("ab\u{10FFFD}\u{10FFFD}c", FragmentStyle::default())]);
+        let notdef: Vec<bool> = render_data
+            .lines()
+            .flat_map(|line| line.runs())
+            .flat_map(|run| run.clusters())
+            .map(|cluster| cluster.is_notdef())
+            .collect();
+
+        assert_eq!(&notdef[..5], &[false, false, true, true, false]);
+        assert_eq!(render_data.missing_ranges(), vec![2..10]);
+    }
+
+    #[test]
+    fn test_notdef_color_overrides_missing_clusters() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let red = [1., 0., 0., 1.];
+        lcx.set_notdef_color(Some(red));
+        let style = FragmentStyle {
+            color: [0., 0., 1., 1.],
+            ..FragmentStyle::default()
+        };
+        let mut render_data = RenderData::new();
+        let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+        lb.add_text("a\u{10FFFD}b", style);
+        lb.build_into(&mut render_data);
+        render_data
+            .break_lines()
+            .break_without_advance_or_alignment();
+
+        let colors: Vec<[f32; 4]> = render_data
+            .lines()
+            .flat_map(|line| line.runs())
+            .flat_map(|run| run.clusters())
+            .map(|cluster| cluster.color())
+            .collect();
+        assert_eq!(&colors[..3], &[style.color, red, style.color]);
+    }
+
+    #[test]
+    fn test_positioned_glyphs_follow_visual_order() {
+        let mut render_data = synthetic(&[0, 1], 2, 10.);
+        for (i, glyph) in render_data.data.glyphs.iter_mut().enumerate() {
+            *glyph = GlyphData::simple(i as u16 + 1, 10., 0);
+        }
+        let line = render_data.lines().next().unwrap();
+
+        let mut origin = 5.;
+        let mut glyphs = Vec::new();
+        for run in line.runs() {
+            glyphs.extend(run.positioned_glyphs(origin).map(|(g, x)| (g.id, x)));
+            origin += run.clusters().map(|c| c.advance()).sum::<f32>();
+        }
+        // The right-to-left run draws its last glyph first.
+        assert_eq!(glyphs, vec![(1, 5.), (2, 15.), (4, 25.), (3, 35.)]);
+    }
+
+    #[test]
+    fn test_visual_clusters_reverse_rtl_runs() {
+        let render_data = render(&[("אבג", FragmentStyle::default())]);
+        let line = render_data.lines().next().unwrap();
+        let run = line.runs().find(|run| run.level() & 1 != 0).unwrap();
+
+        let logical: Vec<usize> = run.clusters().map(|c| c.offset()).collect();
+        let visual: Vec<usize> = run.visual_clusters().map(|c| c.offset()).collect();
+        assert_eq!(logical, vec![0, 2, 4]);
+        assert_eq!(visual, vec![4, 2, 0]);
+    }
+
+    #[test]
+    fn test_line_metrics_stack_without_gaps() {
+        let font_library = FontLibrary::default();
+        let mut lcx = LayoutContext::new(&font_library);
+        let mut render_data = RenderData::new();
+        let mut lb = lcx.builder(Direction::LeftToRight, None, 1.);
+        lb.add_text("first", FragmentStyle::default());
+        lb.new_line();
+        lb.add_text(
+            "second",
+            FragmentStyle {
+                line_spacing: 1.5,
+                ..FragmentStyle::default()
+            },
+        );
+        lb.new_line();
+        lb.add_text("third", FragmentStyle::default());
+        lb.build_into(&mut render_data);
+        render_data
+            .break_lines()
+            .break_without_advance_or_alignment();
+
+        let metrics: Vec<LineMetrics> = render_data.line_metrics().collect();
+        assert_eq!(metrics.len(), 3);
+        assert_eq!(metrics[0].top, 0.);
+        for pair in metrics.windows(2) {
+            assert_eq!(pair[0].bottom, pair[1].top);
+        }
+        assert!(metrics[1].bottom - metrics[1].top > metrics[0].bottom - metrics[0].top);
+        let heights: f32 = metrics.iter().map(|m| m.bottom - m.top).sum();
+        assert_eq!(heights, metrics[2].bottom);
+    }
+}
@@ -11,12 +11,15 @@
 
 use crate::layout::builder_data::FontSettingKey;
 use crate::layout::builder_data::EMPTY_FONT_SETTINGS;
-use crate::sugarloaf::primitives::SugarCursor;
+use crate::sugarloaf::primitives::{StrikethroughInfo, SugarCursor};
 use crate::Sugar;
 use crate::SugarDecoration;
+use crate::SugarGraphic;
 use crate::SugarStyle;
 // pub use swash::text::Language;
+use std::hash::{Hash, Hasher};
 use swash::{Stretch, Style, Weight};
+use unicode_width::UnicodeWidthChar;
 
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub struct FragmentStyle {
@@ -34,6 +37,20 @@ pub struct FragmentStyle {
     pub font_size: f32,
     /// Font color.
     pub color: [f32; 4],
+    /// Opacity applied on top of `color`, e.g. below 1 for faint text.
+    pub alpha: f32,
+    /// Faint text, see `FragmentStyle::apply_dim`.
+    pub dim: bool,
+    /// Reverse video, see `FragmentStyle::apply_reverse`.
+    pub reversed: bool,
+    /// Text is laid out, selectable and copyable, but drawn transparent.
+    pub concealed: bool,
+    /// Glyphs take no horizontal space, e.g. to overstrike the previous
+    /// fragment or to fix combining marks the shaper misplaced.
+    pub force_zero_advance: bool,
+    /// Vertical offset of the glyphs from the baseline, positive values
+    /// raise superscripts and negative ones lower subscripts.
+    pub baseline_shift: f32,
     /// Background color.
     pub background_color: Option<[f32; 4]>,
     /// Font features.
@@ -54,10 +71,25 @@ pub struct FragmentStyle {
     pub underline_color: Option<[f32; 4]>,
     /// Thickness of an underline.
     pub underline_size: Option<f32>,
+    /// Strikethrough decoration.
+    pub strikethrough: Option<StrikethroughInfo>,
     /// Text case transformation.
     // pub text_transform: TextTransform,
     /// Cursor
     pub cursor: SugarCursor,
+    /// Whether the cursor blinks. Compositors keep the shaped run and
+    /// only toggle the cursor visibility on their blink interval, so
+    /// switching the visibility never requires reshaping. The flag itself
+    /// is style data and must be part of the line hash.
+    pub cursor_blink: bool,
+    /// Forces emoji (`Some(true)`) or text (`Some(false)`) presentation
+    /// by appending the matching variation selector to every character.
+    pub emoji_presentation: Option<bool>,
+    /// Amount of terminal columns taken by each character of the
+    /// fragment, 2 for wide characters and 0 for zero width ones.
+    pub width: f32,
+    /// Graphic displayed by the fragment.
+    pub media: Option<SugarGraphic>,
 }
 
 impl Default for FragmentStyle {
@@ -75,18 +107,151 @@ impl Default for FragmentStyle {
             word_spacing: 0.,
             line_spacing: 1.,
             color: [1.0, 1.0, 1.0, 1.0],
+            alpha: 1.0,
+            dim: false,
+            reversed: false,
+            concealed: false,
+            force_zero_advance: false,
+            baseline_shift: 0.,
             background_color: None,
             cursor: SugarCursor::Disabled,
+            cursor_blink: false,
+            emoji_presentation: None,
+            width: 1.,
+            media: None,
             underline: false,
             underline_offset: None,
             underline_color: None,
             underline_size: None,
+            strikethrough: None,
             // text_transform: TextTransform::None,
         }
     }
 }
 
+impl Hash for FragmentStyle {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.font.hash(state);
+        self.font_attrs.hash(state);
+        self.font_size.to_bits().hash(state);
+        for channel in self.color {
+            channel.to_bits().hash(state);
+        }
+        self.alpha.to_bits().hash(state);
+        self.dim.hash(state);
+        self.reversed.hash(state);
+        self.concealed.hash(state);
+        self.force_zero_advance.hash(state);
+        self.baseline_shift.to_bits().hash(state);
+        if let Some(color) = self.background_color {
+            for channel in color {
+                channel.to_bits().hash(state);
+            }
+        }
+        self.font_features.hash(state);
+        self.font_vars.hash(state);
+        self.letter_spacing.to_bits().hash(state);
+        self.word_spacing.to_bits().hash(state);
+        self.line_spacing.to_bits().hash(state);
+        self.underline.hash(state);
+        self.underline_offset.map(f32::to_bits).hash(state);
+        if let Some(color) = self.underline_color {
+            for channel in color {
+                channel.to_bits().hash(state);
+            }
+        }
+        self.underline_size.map(f32::to_bits).hash(state);
+        if let Some(strikethrough) = self.strikethrough {
+            strikethrough.size.to_bits().hash(state);
+            strikethrough.is_doubled.hash(state);
+        }
+        self.cursor.hash(state);
+        self.cursor_blink.hash(state);
+        self.emoji_presentation.hash(state);
+        self.width.to_bits().hash(state);
+        if let Some(media) = self.media {
+            media.id.hash(state);
+            media.width.hash(state);
+            media.height.hash(state);
+            media.offset.0.to_bits().hash(state);
+            media.offset.1.to_bits().hash(state);
+            if let Some(crop) = media.crop {
+                crop.x.hash(state);
+                crop.y.hash(state);
+                crop.width.hash(state);
+                crop.height.hash(state);
+            }
+        }
+    }
+}
+
 impl FragmentStyle {
+    /// Returns the style with `color` as font color.
+    #[inline]
+    pub fn with_color(mut self, color: [f32; 4]) -> Self {
+        self.color = color;
+        self
+    }
+
+    /// Returns the style with `background` as background color.
+    #[inline]
+    pub fn with_background(mut self, background: [f32; 4]) -> Self {
+        self.background_color = Some(background);
+        self
+    }
+
+    /// Returns the underlined style, drawn with `color` or the font color
+    /// when `None`.
+    #[inline]
+    pub fn with_underline(mut self, color: Option<[f32; 4]>) -> Self {
+        self.underline = true;
+        self.underline_color = color;
+        self
+    }
+
+    /// Returns the style with `cursor` drawn over the fragment.
+    #[inline]
+    pub fn with_cursor(mut self, cursor: SugarCursor) -> Self {
+        self.cursor = cursor;
+        self
+    }
+
+    /// Swaps the color and background of a reversed fragment, taking
+    /// `background` when the fragment has none. Apply it before
+    /// `apply_dim` so faint reversed text dims the swapped color.
+    #[inline]
+    pub fn apply_reverse(&mut self, background: [f32; 4]) {
+        if self.reversed {
+            let background = self.background_color.unwrap_or(background);
+            self.background_color = Some(self.color);
+            self.color = background;
+        }
+    }
+
+    /// Blends the color of a dim fragment halfway toward its background,
+    /// or toward `background` when the fragment has none. Only the color
+    /// changes, so bold and dim text keeps the bold font.
+    #[inline]
+    pub fn apply_dim(&mut self, background: [f32; 4]) {
+        if self.dim {
+            let background = self.background_color.unwrap_or(background);
+            for (channel, background) in self.color[..3].iter_mut().zip(background) {
+                *channel = (*channel + background) * 0.5;
+            }
+        }
+    }
+
+    /// Variation selector requested by `emoji_presentation`.
+    #[inline]
+    pub fn presentation_selector(&self) -> Option<char> {
+        match self.emoji_presentation {
+            Some(true) => Some('\u{FE0F}'),
+            Some(false) => Some('\u{FE0E}'),
+            None => None,
+        }
+    }
+
     pub fn scaled_default(scale: f32) -> Self {
         Self {
             // dir: Direction::LeftToRight,
@@ -101,12 +266,23 @@ impl FragmentStyle {
             word_spacing: 0.,
             line_spacing: 1.,
             color: [1.0, 1.0, 1.0, 1.0],
+            alpha: 1.0,
+            dim: false,
+            reversed: false,
+            concealed: false,
+            force_zero_advance: false,
+            baseline_shift: 0.,
             background_color: None,
             cursor: SugarCursor::Disabled,
+            cursor_blink: false,
+            emoji_presentation: None,
+            width: 1.,
+            media: None,
             underline: false,
             underline_offset: None,
             underline_color: None,
             underline_size: None,
+            strikethrough: None,
             // text_transform: TextTransform::None,
         }
     }
@@ -114,7 +290,10 @@ impl FragmentStyle {
 
 impl From<&Sugar> for FragmentStyle {
     fn from(sugar: &Sugar) -> Self {
-        let mut style = FragmentStyle::default();
+        let mut style = FragmentStyle {
+            width: sugar.content.width().unwrap_or(1) as f32,
+            ..FragmentStyle::default()
+        };
 
         match sugar.style {
             SugarStyle::BoldItalic => {
@@ -143,6 +322,9 @@ impl From<&Sugar> for FragmentStyle {
             SugarCursor::Block(cursor_color) => {
                 style.cursor = SugarCursor::Block(cursor_color);
             }
+            SugarCursor::HollowBlock(cursor_color) => {
+                style.cursor = SugarCursor::HollowBlock(cursor_color);
+            }
             SugarCursor::Caret(cursor_color) => {
                 style.cursor = SugarCursor::Caret(cursor_color);
             }
@@ -157,16 +339,15 @@ impl From<&Sugar> for FragmentStyle {
                     style.underline_size = Some(1.);
                 }
             }
-            SugarDecoration::Strikethrough => {
-                style.underline = true;
-                style.underline_offset = Some(style.font_size / 2.);
-                style.underline_size = Some(2.);
+            SugarDecoration::Strikethrough(info) => {
+                style.strikethrough = Some(info);
             }
             _ => {}
         }
 
         style.color = sugar.foreground_color;
         style.background_color = sugar.background_color;
+        style.media = sugar.media;
 
         style
     }
@@ -194,3 +375,100 @@ pub enum TextTransform {
     Lowercase,
     Capitalize,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::hash::DefaultHasher;
+
+    fn hash_key(style: &FragmentStyle) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        style.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn test_hash_includes_width_and_font_vars() {
+        let style = FragmentStyle::default();
+        assert_eq!(hash_key(&style), hash_key(&FragmentStyle::default()));
+
+        let wide = FragmentStyle {
+            width: 2.,
+            ..FragmentStyle::default()
+        };
+        assert_ne!(hash_key(&style), hash_key(&wide));
+
+        let varied = FragmentStyle {
+            font_vars: 1,
+            ..FragmentStyle::default()
+        };
+        assert_ne!(hash_key(&style), hash_key(&varied));
+    }
+
+    #[test]
+    fn test_faint_keeps_base_color() {
+        let normal = FragmentStyle {
+            color: [0.5, 0.6, 0.7, 1.],
+            ..FragmentStyle::default()
+        };
+        let faint = FragmentStyle {
+            alpha: 0.5,
+            ..normal
+        };
+        assert_eq!(normal.alpha, 1.);
+        assert_eq!(faint.color, normal.color);
+        assert_ne!(faint.alpha, normal.alpha);
+        assert_ne!(hash_key(&faint), hash_key(&normal));
+    }
+
+    #[test]
+    fn test_dim_blends_toward_background() {
+        let mut style = FragmentStyle {
+            color: [1., 0.5, 0., 1.],
+            dim: true,
+            ..FragmentStyle::default()
+        };
+        assert_ne!(
+            hash_key(&style),
+            hash_key(&FragmentStyle {
+                dim: false,
+                ..style
+            })
+        );
+        style.apply_dim([0., 0.5, 1., 1.]);
+        assert_eq!(style.color, [0.5, 0.5, 0.5, 1.]);
+
+        // The fragment background wins over the surface one.
+        let mut style = FragmentStyle {
+            color: [1., 1., 1., 1.],
+            background_color: Some([0., 0., 0., 1.]),
+            dim: true,
+            ..FragmentStyle::default()
+        };
+        style.apply_dim([1., 1., 1., 1.]);
+        assert_eq!(style.color, [0.5, 0.5, 0.5, 1.]);
+
+        let mut bright = FragmentStyle::default();
+        bright.apply_dim([0., 0., 0., 1.]);
+        assert_eq!(bright.color, FragmentStyle::default().color);
+    }
+
+    #[test]
+    fn test_with_methods_match_field_assignment() {
+        let manual = FragmentStyle {
+            color: [1., 0., 0., 1.],
+            background_color: Some([0., 0., 1., 1.]),
+            underline: true,
+            underline_color: Some([0., 1., 0., 1.]),
+            cursor: SugarCursor::Caret([1., 1., 1., 1.]),
+            ..FragmentStyle::default()
+        };
+        let built = FragmentStyle::default()
+            .with_color([1., 0., 0., 1.])
+            .with_background([0., 0., 1., 1.])
+            .with_underline(Some([0., 1., 0., 1.]))
+            .with_cursor(SugarCursor::Caret([1., 1., 1., 1.]));
+        assert_eq!(built, manual);
+        assert_eq!(hash_key(&built), hash_key(&manual));
+    }
+}
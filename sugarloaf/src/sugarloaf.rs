@@ -182,6 +182,12 @@ impl Sugarloaf<'_> {
     #[inline]
     pub fn set_background_color(&mut self, color: wgpu::Color) -> &mut Self {
         self.background_color = color;
+        self.state.compositors.advanced.set_background_color([
+            color.r as f32,
+            color.g as f32,
+            color.b as f32,
+            color.a as f32,
+        ]);
         self
     }
 
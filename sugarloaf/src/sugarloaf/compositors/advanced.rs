@@ -18,6 +18,7 @@ pub struct Advanced {
     pub mocked_render_data: RenderData,
     content_builder: ContentBuilder,
     layout_context: LayoutContext,
+    background_color: [f32; 4],
 }
 
 impl Advanced {
@@ -27,6 +28,18 @@ impl Advanced {
             content_builder: ContentBuilder::default(),
             render_data: RenderData::new(),
             mocked_render_data: RenderData::new(),
+            background_color: [0., 0., 0., 1.],
+        }
+    }
+
+    /// Sets the surface background that dim fragments blend toward and
+    /// reversed fragments without a background use as their color.
+    #[inline]
+    pub fn set_background_color(&mut self, background_color: [f32; 4]) {
+        if self.background_color != background_color {
+            self.background_color = background_color;
+            // Line hashes don't cover the blended colors.
+            self.layout_context.clear_cache();
         }
     }
 
@@ -51,11 +64,14 @@ impl Advanced {
     #[inline]
     pub fn set_fonts(&mut self, fonts: &FontLibrary) {
         self.layout_context = LayoutContext::new(fonts);
+        self.render_data = RenderData::default();
     }
 
     #[inline]
     pub fn update_layout(&mut self, tree: &SugarTree) {
-        self.render_data = RenderData::default();
+        // Clearing keeps the capacity of the previous frame around.
+        self.render_data.clear();
+        self.layout_context.ensure_cache_capacity(tree.layout.lines);
 
         let mut lb = self.layout_context.builder(
             Direction::LeftToRight,
@@ -64,7 +80,6 @@ impl Advanced {
         );
         let content = self.content_builder.build_ref();
         content.layout(&mut lb);
-        self.render_data.clear();
         lb.build_into(&mut self.render_data);
         self.render_data
             .break_lines()
@@ -108,10 +123,12 @@ impl Advanced {
 
         let line = &tree.lines[line_number];
         for sugar in line.inner() {
-            let style = FragmentStyle {
+            let mut style = FragmentStyle {
                 font_size: tree.layout.font_size,
                 ..FragmentStyle::from(sugar)
             };
+            style.apply_reverse(self.background_color);
+            style.apply_dim(self.background_color);
 
             if sugar.repeated > 0 {
                 let text = std::iter::repeat(sugar.content)
@@ -3,8 +3,9 @@
 // This source code is licensed under the MIT license found in the
 // LICENSE file in the root directory of this source tree.
 
-use crate::components::core::image::Handle;
+use crate::components::core::{image::Handle, shapes::Rectangle};
 use fnv::FnvHashMap;
+use std::collections::HashSet;
 
 pub struct SugarGraphicEntry {
     pub id: SugarGraphicId,
@@ -42,7 +43,7 @@ impl SugarloafGraphics {
         let handle = Handle::from_pixels(
             graphic_data.width as u32,
             graphic_data.height as u32,
-            graphic_data.pixels.clone(),
+            graphic_data.clone().to_rgba().pixels,
         );
         self.inner
             .entry(graphic_data.id)
@@ -56,13 +57,27 @@ impl SugarloafGraphics {
     pub fn remove(&mut self, graphic_id: &SugarGraphicId) {
         self.inner.remove(graphic_id);
     }
+
+    /// Drops every graphic that isn't in `live`. `live` must hold every
+    /// graphic still referenced by the terminal, scrollback included,
+    /// since graphics are only added once. The visible tree alone is not
+    /// enough, images scrolled back into view would be gone.
+    #[inline]
+    pub fn retain(&mut self, live: &HashSet<SugarGraphicId>) {
+        self.inner.retain(|id, _| live.contains(id));
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct SugarGraphic {
     pub id: SugarGraphicId,
     pub width: u16,
     pub height: u16,
+    /// Offset, in pixels, of the graphic inside its first cell.
+    pub offset: (f32, f32),
+    /// Region of the source image to display, in pixels. The whole image
+    /// is displayed if it's `None`.
+    pub crop: Option<Rectangle<u32>>,
 }
 
 /// Unique identifier for every graphic added to a grid.
@@ -102,6 +117,57 @@ pub struct SugarGraphicData {
 }
 
 impl SugarGraphicData {
+    /// Creates a graphic from RGBA pixels. Rows may be padded at the end,
+    /// the stride is taken from the length of `pixels` and the padding is
+    /// dropped. The id is assigned when the graphic is inserted in the grid.
+    pub fn from_rgba(width: usize, height: usize, pixels: Vec<u8>) -> Self {
+        let pixels = unpad_rows(pixels, width * 4, height);
+        let is_opaque = pixels.chunks_exact(4).all(|pixel| pixel[3] == 255);
+
+        SugarGraphicData {
+            id: SugarGraphicId(0),
+            width,
+            height,
+            color_type: ColorType::Rgba,
+            pixels,
+            is_opaque,
+        }
+    }
+
+    /// Creates a graphic from RGB pixels, rows may be padded as in
+    /// [`SugarGraphicData::from_rgba`].
+    pub fn from_rgb(width: usize, height: usize, pixels: Vec<u8>) -> Self {
+        SugarGraphicData {
+            id: SugarGraphicId(0),
+            width,
+            height,
+            color_type: ColorType::Rgb,
+            pixels: unpad_rows(pixels, width * 3, height),
+            is_opaque: true,
+        }
+    }
+
+    /// Converts the pixels to RGBA, which is the layout expected by the
+    /// image handles used for compositing.
+    pub fn to_rgba(self) -> Self {
+        match self.color_type {
+            ColorType::Rgba => self,
+            ColorType::Rgb => {
+                let mut pixels = Vec::with_capacity(self.width * self.height * 4);
+                for pixel in self.pixels.chunks_exact(3) {
+                    pixels.extend_from_slice(&[pixel[0], pixel[1], pixel[2], 255]);
+                }
+
+                SugarGraphicData {
+                    color_type: ColorType::Rgba,
+                    pixels,
+                    is_opaque: true,
+                    ..self
+                }
+            }
+        }
+    }
+
     /// Check if the image may contain transparent pixels. If it returns
     /// `false`, it is guaranteed that there are no transparent pixels.
     #[inline]
@@ -140,3 +206,74 @@ impl SugarGraphicData {
         true
     }
 }
+
+/// Removes the padding at the end of each row, if any.
+fn unpad_rows(pixels: Vec<u8>, row_len: usize, height: usize) -> Vec<u8> {
+    if height == 0 || pixels.len() <= row_len * height {
+        return pixels;
+    }
+
+    let stride = pixels.len() / height;
+    pixels
+        .chunks(stride)
+        .take(height)
+        .flat_map(|row| &row[..row_len.min(row.len())])
+        .copied()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retain_drops_unreferenced_graphics() {
+        let mut graphics = SugarloafGraphics::default();
+        for id in 0..2 {
+            graphics.add(SugarGraphicData {
+                id: SugarGraphicId(id),
+                ..SugarGraphicData::from_rgba(1, 1, vec![0, 0, 0, 255])
+            });
+        }
+
+        let mut live = HashSet::from([SugarGraphicId(0), SugarGraphicId(1)]);
+        graphics.retain(&live);
+        assert_eq!(graphics.keys().len(), 2);
+
+        live.remove(&SugarGraphicId(1));
+        graphics.retain(&live);
+        assert_eq!(graphics.keys(), vec![SugarGraphicId(0)]);
+        assert!(graphics.get(&SugarGraphicId(1)).is_none());
+
+        graphics.retain(&HashSet::new());
+        assert!(graphics.is_empty());
+    }
+
+    #[test]
+    fn test_rgb_to_rgba() {
+        let graphic = SugarGraphicData::from_rgb(2, 1, vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(graphic.color_type, ColorType::Rgb);
+
+        let graphic = graphic.to_rgba();
+        assert_eq!(graphic.color_type, ColorType::Rgba);
+        assert_eq!(graphic.pixels, vec![1, 2, 3, 255, 4, 5, 6, 255]);
+        assert!(graphic.is_opaque);
+        assert!(!graphic.maybe_transparent());
+    }
+
+    #[test]
+    fn test_padded_rows_are_dropped() {
+        // Two rows of one RGB pixel, each padded to a 4 bytes stride.
+        let graphic = SugarGraphicData::from_rgb(1, 2, vec![1, 2, 3, 0, 4, 5, 6, 0]);
+        assert_eq!(graphic.pixels, vec![1, 2, 3, 4, 5, 6]);
+
+        let graphic = SugarGraphicData::from_rgba(
+            1,
+            2,
+            vec![1, 2, 3, 128, 0, 0, 4, 5, 6, 255, 0, 0],
+        );
+        assert_eq!(graphic.pixels, vec![1, 2, 3, 128, 4, 5, 6, 255]);
+        assert!(!graphic.is_opaque);
+        assert_eq!(graphic.clone().to_rgba(), graphic);
+    }
+}
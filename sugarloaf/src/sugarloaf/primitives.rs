@@ -82,36 +82,27 @@ impl Hash for Sugar {
             SugarDecoration::Underline => {
                 1.hash(state);
             }
-            SugarDecoration::Strikethrough => {
+            SugarDecoration::Strikethrough(info) => {
                 2.hash(state);
+                info.size.to_bits().hash(state);
+                info.is_doubled.hash(state);
             }
         };
-        match self.cursor {
-            SugarCursor::Disabled => {
-                0.hash(state);
-            }
-            SugarCursor::Block(color) => {
-                1.hash(state);
-                color[0].to_bits().hash(state);
-                color[1].to_bits().hash(state);
-                color[2].to_bits().hash(state);
-                color[3].to_bits().hash(state);
-            }
-            SugarCursor::Caret(color) => {
-                2.hash(state);
-                color[0].to_bits().hash(state);
-                color[1].to_bits().hash(state);
-                color[2].to_bits().hash(state);
-                color[3].to_bits().hash(state);
-            }
-            SugarCursor::Underline(color) => {
-                3.hash(state);
-                color[0].to_bits().hash(state);
-                color[1].to_bits().hash(state);
-                color[2].to_bits().hash(state);
-                color[3].to_bits().hash(state);
+        self.cursor.hash(state);
+
+        if let Some(media) = self.media {
+            media.id.hash(state);
+            media.width.hash(state);
+            media.height.hash(state);
+            media.offset.0.to_bits().hash(state);
+            media.offset.1.to_bits().hash(state);
+            if let Some(crop) = media.crop {
+                crop.x.hash(state);
+                crop.y.hash(state);
+                crop.width.hash(state);
+                crop.height.hash(state);
             }
-        };
+        }
     }
 }
 
@@ -140,20 +131,69 @@ fn equal_without_consider_repeat(sugar_a: &Sugar, sugar_b: &Sugar) -> bool {
 #[derive(Debug, Default, PartialEq, Copy, Clone)]
 pub enum SugarCursor {
     Block([f32; 4]),
+    /// Block drawn only by its border, used for unfocused windows.
+    HollowBlock([f32; 4]),
     Caret([f32; 4]),
     Underline([f32; 4]),
     #[default]
     Disabled,
 }
 
+impl Hash for SugarCursor {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            SugarCursor::Disabled => {
+                0.hash(state);
+            }
+            SugarCursor::Block(color) => {
+                1.hash(state);
+                color[0].to_bits().hash(state);
+                color[1].to_bits().hash(state);
+                color[2].to_bits().hash(state);
+                color[3].to_bits().hash(state);
+            }
+            SugarCursor::Caret(color) => {
+                2.hash(state);
+                color[0].to_bits().hash(state);
+                color[1].to_bits().hash(state);
+                color[2].to_bits().hash(state);
+                color[3].to_bits().hash(state);
+            }
+            SugarCursor::Underline(color) => {
+                3.hash(state);
+                color[0].to_bits().hash(state);
+                color[1].to_bits().hash(state);
+                color[2].to_bits().hash(state);
+                color[3].to_bits().hash(state);
+            }
+            SugarCursor::HollowBlock(color) => {
+                4.hash(state);
+                color[0].to_bits().hash(state);
+                color[1].to_bits().hash(state);
+                color[2].to_bits().hash(state);
+                color[3].to_bits().hash(state);
+            }
+        };
+    }
+}
+
 #[derive(Debug, Copy, PartialEq, Default, Clone)]
 pub enum SugarDecoration {
     Underline,
-    Strikethrough,
+    Strikethrough(StrikethroughInfo),
     #[default]
     Disabled,
 }
 
+#[derive(Debug, Copy, PartialEq, Default, Clone)]
+pub struct StrikethroughInfo {
+    /// Thickness of the stroke, zero uses the font strikeout size.
+    pub size: f32,
+    /// Draws two strokes instead of one.
+    pub is_doubled: bool,
+}
+
 #[derive(Debug, PartialEq, Default, Copy, Clone)]
 pub enum SugarStyle {
     #[default]
@@ -342,6 +382,8 @@ impl Index<usize> for SugarLine {
 #[cfg(test)]
 pub mod test {
     use super::*;
+    use crate::components::core::shapes::Rectangle;
+    use crate::sugarloaf::graphics::SugarGraphicId;
 
     #[test]
     fn test_sugarelement_comparisson_exact_match() {
@@ -623,13 +665,106 @@ pub mod test {
             foreground_color: [0., 0., 0., 0.],
             background_color: None,
             style: SugarStyle::Disabled,
-            decoration: SugarDecoration::Strikethrough,
+            decoration: SugarDecoration::Strikethrough(StrikethroughInfo::default()),
             cursor: SugarCursor::Disabled,
             media: None,
         };
         assert!(sugar_b.hash_key() != sugar_c.hash_key());
     }
 
+    #[test]
+    fn test_sugar_hash_with_hollow_block_cursor() {
+        let color = [1., 1., 1., 1.];
+        let block = Sugar {
+            content: 'a',
+            cursor: SugarCursor::Block(color),
+            ..Sugar::default()
+        };
+        let hollow = Sugar {
+            content: 'a',
+            cursor: SugarCursor::HollowBlock(color),
+            ..Sugar::default()
+        };
+        assert!(block.hash_key() != hollow.hash_key());
+        assert!(block != hollow);
+    }
+
+    #[test]
+    fn test_sugar_hash_with_doubled_strikethrough() {
+        let single = Sugar {
+            content: 'a',
+            decoration: SugarDecoration::Strikethrough(StrikethroughInfo {
+                size: 1.,
+                is_doubled: false,
+            }),
+            ..Sugar::default()
+        };
+        let doubled = Sugar {
+            content: 'a',
+            decoration: SugarDecoration::Strikethrough(StrikethroughInfo {
+                size: 1.,
+                is_doubled: true,
+            }),
+            ..Sugar::default()
+        };
+        assert!(single.hash_key() != doubled.hash_key());
+
+        let thicker = Sugar {
+            content: 'a',
+            decoration: SugarDecoration::Strikethrough(StrikethroughInfo {
+                size: 2.,
+                is_doubled: false,
+            }),
+            ..Sugar::default()
+        };
+        assert!(single.hash_key() != thicker.hash_key());
+    }
+
+    #[test]
+    fn test_sugar_hash_with_media_offset() {
+        let graphic = SugarGraphic {
+            id: SugarGraphicId(1),
+            width: 10,
+            height: 10,
+            offset: (0., 0.),
+            crop: None,
+        };
+        let a = Sugar {
+            media: Some(graphic),
+            ..Sugar::default()
+        };
+        let b = Sugar {
+            media: Some(SugarGraphic {
+                offset: (2., 0.),
+                ..graphic
+            }),
+            ..Sugar::default()
+        };
+        assert!(a.hash_key() != b.hash_key());
+
+        // The run cache is keyed by the line hash, so moving the image
+        // inside its cell must produce a different line key.
+        let mut line_a = SugarLine::default();
+        line_a.from_vec(&[a]);
+        let mut line_b = SugarLine::default();
+        line_b.from_vec(&[b]);
+        assert!(line_a.hash_key() != line_b.hash_key());
+
+        let cropped = Sugar {
+            media: Some(SugarGraphic {
+                crop: Some(Rectangle {
+                    x: 0,
+                    y: 0,
+                    width: 5,
+                    height: 5,
+                }),
+                ..graphic
+            }),
+            ..Sugar::default()
+        };
+        assert!(a.hash_key() != cropped.hash_key());
+    }
+
     #[test]
     fn test_sugar_line_hash() {
         let mut line_a = SugarLine::default();
@@ -4,7 +4,8 @@
 // LICENSE file in the root directory of this source tree.
 
 use crate::sugarloaf::SugarloafLayout;
-use crate::{Sugar, SugarBlock, SugarLine};
+use crate::{Sugar, SugarBlock, SugarGraphicId, SugarLine};
+use std::collections::HashSet;
 
 #[derive(Debug, Default, Copy, Clone, PartialEq)]
 pub struct DiffChar {
@@ -144,6 +145,15 @@ impl SugarTree {
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
+
+    /// Graphics referenced by any sugar of the tree.
+    pub fn graphics(&self) -> HashSet<SugarGraphicId> {
+        self.lines
+            .iter()
+            .flat_map(|line| line.inner().iter())
+            .filter_map(|sugar| sugar.media.map(|media| media.id))
+            .collect()
+    }
 }
 
 #[cfg(test)]